@@ -0,0 +1,174 @@
+//! Stable names for sensors, so that swapping a probe doesn't need a config change
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, offset::Utc};
+use serde::{Serialize,Deserialize};
+
+use super::config::Config;
+use super::fridge::INTERNAL_SERIES;
+use super::types::Readings;
+#[cfg(test)]
+use super::types::{Reading, Source};
+
+/// Aliases that `Fridge` gives special meaning. Others are just recorded.
+pub const ROLES: [&str; 3] = ["wort", "fridge", "ambient"];
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Aliases {
+    /// Sensor id to alias
+    pub names: BTreeMap<String, String>,
+}
+
+/// What is known about a discovered sensor
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SensorInfo {
+    pub id: String,
+    pub alias: Option<String>,
+    pub temp: Option<f32>,
+    pub raw: Option<f32>,
+    pub last_seen: Option<DateTime<Utc>>,
+    /// How long the latest read took
+    pub latency: Option<Duration>,
+    /// Failed reads since startup
+    pub errors: u64,
+}
+
+impl SensorInfo {
+    pub fn new(id: &str) -> Self {
+        SensorInfo {
+            id: id.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Aliases {
+    pub const FILENAME: &'static str = "fridgyeast-sensors.conf";
+
+    /// Initial aliases from `wort_name` and `fridge_name` in the config
+    fn seed(config: &Config) -> Aliases {
+        let mut a = Aliases::default();
+        a.names.insert(config.wort_name.clone(), "wort".into());
+        a.names.insert(config.fridge_name.clone(), "fridge".into());
+        a
+    }
+
+    fn try_load(path: &Path) -> Result<Aliases> {
+        let mut s = String::new();
+        File::open(path)?.read_to_string(&mut s)?;
+        Ok(serde_json::from_str(&s)?)
+    }
+
+    pub fn load(config: &Config) -> Result<Aliases> {
+        let path = config.params_dir.join(Aliases::FILENAME);
+        Self::try_load(&path)
+        .or_else(|e| {
+            let missing = match e.root_cause().downcast_ref::<std::io::Error>() {
+                Some(ioe) => ioe.kind() == std::io::ErrorKind::NotFound,
+                None => false,
+            };
+            if !missing {
+                error!("Problem reading sensor aliases, will use config names. {}", e);
+            }
+            let a = Aliases::seed(config);
+            a.save(config).context("writing new sensor aliases")?;
+            Ok(a)
+        })
+    }
+
+    pub fn save(&self, config: &Config) -> Result<()> {
+        let path = config.params_dir.join(Aliases::FILENAME);
+        let af = atomicwrites::AtomicFile::new(path, atomicwrites::AllowOverwrite);
+        af.write(|mut f| {
+            serde_json::ser::to_writer(&mut f, self)?;
+            f.write_all(b"\n")
+        }).map_err(|e| anyhow!("Writing sensor aliases failed: {}", e))
+    }
+
+    /// Sets the alias for a sensor id, or removes it if `alias` is empty.
+    /// An alias can only belong to one sensor, it's taken from any other.
+    pub fn set(&mut self, id: &str, alias: &str) -> Result<()> {
+        let alias = alias.trim();
+        if alias.len() > 30 {
+            bail!("Alias is too long");
+        }
+        // series names are used in urls and export lists
+        if !alias.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            bail!("Alias can only have letters, numbers, - and _");
+        }
+        if INTERNAL_SERIES.contains(&alias) {
+            bail!("'{alias}' is reserved");
+        }
+
+        self.names.retain(|_, a| a != alias);
+        if alias.is_empty() {
+            self.names.remove(id);
+        } else {
+            self.names.insert(id.into(), alias.into());
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<&str> {
+        self.names.get(id).map(|a| a.as_str())
+    }
+
+    /// Renames readings by alias. Sensors without an alias keep their id.
+    pub fn apply(&self, r: &Readings) -> Readings {
+        let mut out = Readings::new();
+        for (id, reading) in &r.temps {
+            let name = self.get(id).unwrap_or(id);
+            out.temps.insert(name.into(), reading.clone());
+        }
+        out.failed = r.failed.iter()
+            .map(|id| self.get(id).unwrap_or(id).to_string())
+            .collect();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aliases() {
+        let mut a = Aliases::default();
+        a.set("28-aa", "wort").unwrap();
+        a.set("28-bb", "fridge").unwrap();
+        assert!(a.set("28-cc", "bad name").is_err());
+        for reserved in ["events", "setpoint", "compressor", "running",
+            "fridge.unfiltered", "wort.unfiltered"] {
+            assert!(a.set("28-cc", reserved).is_err(), "{reserved}");
+        }
+
+        // moving a role to a new probe
+        a.set("28-cc", "wort").unwrap();
+        assert_eq!(a.get("28-aa"), None);
+        assert_eq!(a.get("28-cc"), Some("wort"));
+
+        let mut r = Readings::new();
+        r.add("28-bb", Reading::new(4.0, Source::Test));
+        r.add("28-cc", Reading::new(18.0, Source::Test));
+        r.add("28-dd", Reading::new(25.0, Source::Test));
+        r.failed.push("28-aa".into());
+        let r = a.apply(&r);
+        assert_eq!(r.get_temp("wort", Duration::MAX), Some(18.0));
+        assert_eq!(r.get_temp("fridge", Duration::MAX), Some(4.0));
+        assert_eq!(r.get_temp("28-dd", Duration::MAX), Some(25.0));
+        assert_eq!(r.failed, vec!["28-aa".to_string()]);
+
+        a.set("28-cc", "").unwrap();
+        assert_eq!(a.get("28-cc"), None);
+    }
+}
@@ -0,0 +1,376 @@
+//! The current brew, and its fermentation progress from gravity readings
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use chrono::{DateTime, Duration, offset::Utc};
+use serde::{Serialize,Deserialize};
+
+use super::config::Config;
+use super::timeseries::Seq;
+
+/// Gravity changing less than this over `batch_stable_days` is stable
+const STABLE_CHANGE: f32 = 0.001;
+/// Finished if stable within this of the target FG
+const FG_MARGIN: f32 = 0.003;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub name: String,
+    /// Original gravity, as SG
+    pub og: f32,
+    pub target_fg: Option<f32>,
+    /// Unix seconds
+    pub started: i64,
+    /// Series with gravity readings, such as `ispindel-blue.gravity`
+    #[serde(default)]
+    pub gravity_series: String,
+    #[serde(default)]
+    pub style: Option<String>,
+    #[serde(default)]
+    pub yeast: Option<String>,
+    /// Setpoint profile to follow, empty to leave the setpoint alone
+    #[serde(default)]
+    pub profile: Vec<Step>,
+    /// Index into `profile`
+    #[serde(default)]
+    pub step: usize,
+    /// Unix seconds
+    #[serde(default)]
+    pub step_started: i64,
+}
+
+/// A stage of a fermentation profile
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Step {
+    pub name: String,
+    pub setpoint: f32,
+    /// How long to hold, the last step is held until the batch ends
+    pub days: f32,
+    /// Move on early once apparent attenuation reaches this percent
+    #[serde(default)]
+    pub until_attenuation: Option<f32>,
+}
+
+impl Step {
+    pub fn new(name: &str, setpoint: f32, days: f32) -> Self {
+        Step {
+            name: name.into(),
+            setpoint,
+            days,
+            until_attenuation: None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// No gravity readings yet
+    Waiting,
+    Fermenting,
+    /// Gravity is stable but short of the target
+    Stalled,
+    Finished,
+}
+
+impl std::fmt::Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            State::Waiting => "waiting for gravity",
+            State::Fermenting => "fermenting",
+            State::Stalled => "stalled",
+            State::Finished => "finished",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// Current SG
+    pub gravity: Option<f32>,
+    /// Apparent attenuation, 0 to 1
+    pub attenuation: Option<f32>,
+    /// Estimated ABV percent
+    pub abv: Option<f32>,
+    /// SG change per day, negative while fermenting
+    pub per_day: Option<f32>,
+    pub state: State,
+}
+
+impl Batch {
+    const FILENAME: &'static str = "fridgyeast-batch.conf";
+
+    pub fn started_time(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp(self.started, 0).unwrap_or_default()
+    }
+
+    pub fn current_step(&self) -> Option<&Step> {
+        self.profile.get(self.step)
+    }
+
+    /// When the current step ends by time, if it does
+    pub fn step_end(&self) -> Option<DateTime<Utc>> {
+        let step = self.current_step()?;
+        if step.days <= 0.0 || self.step + 1 >= self.profile.len() {
+            return None
+        }
+        let start = DateTime::from_timestamp(self.step_started, 0).unwrap_or_default();
+        Some(start + Duration::seconds((step.days * 86400.0) as i64))
+    }
+
+    /// Moves to the next profile step once the current one is done.
+    /// `attenuation` is 0 to 1. Returns true if the step changed.
+    pub fn advance(&mut self, now: DateTime<Utc>, attenuation: Option<f32>) -> bool {
+        let Some(step) = self.current_step() else {
+            return false
+        };
+        if self.step + 1 >= self.profile.len() {
+            return false
+        }
+        let timed = self.step_end().map(|end| now >= end).unwrap_or(false);
+        let attenuated = match (step.until_attenuation, attenuation) {
+            (Some(target), Some(a)) => a * 100.0 >= target,
+            _ => false,
+        };
+        if !(timed || attenuated) {
+            return false
+        }
+        self.step += 1;
+        self.step_started = now.timestamp();
+        true
+    }
+
+    fn try_load(path: &Path) -> Result<Batch> {
+        let mut s = String::new();
+        File::open(path)?.read_to_string(&mut s)?;
+        Ok(serde_json::from_str(&s)?)
+    }
+
+    /// Returns `None` if no batch has been started
+    pub fn load(config: &Config) -> Result<Option<Batch>> {
+        let path = config.params_dir.join(Batch::FILENAME);
+        match Self::try_load(&path) {
+            Ok(b) => Ok(Some(b)),
+            Err(e) => {
+                match e.root_cause().downcast_ref::<std::io::Error>() {
+                    Some(ioe) if ioe.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                    _ => Err(e).context("Problem reading batch"),
+                }
+            }
+        }
+    }
+
+    pub fn save(&self, config: &Config) -> Result<()> {
+        let path = config.params_dir.join(Batch::FILENAME);
+        let af = atomicwrites::AtomicFile::new(path, atomicwrites::AllowOverwrite);
+        af.write(|mut f| {
+            serde_json::ser::to_writer(&mut f, self)?;
+            f.write_all(b"\n")
+        }).map_err(|e| anyhow!("Writing batch failed: {}", e))
+    }
+
+    pub fn remove(config: &Config) -> Result<()> {
+        let path = config.params_dir.join(Batch::FILENAME);
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.name.trim().is_empty() || self.name.len() > 100 {
+            bail!("Batch needs a name");
+        }
+        if !(1.0..1.2).contains(&self.og) {
+            bail!("OG should be a gravity like 1.050");
+        }
+        if let Some(fg) = self.target_fg {
+            if !(0.98..self.og).contains(&fg) {
+                bail!("Target FG should be below the OG");
+            }
+        }
+        for st in &self.profile {
+            if !(-5.0..40.0).contains(&st.setpoint) {
+                bail!("Step '{}' setpoint {:.1}° is out of range", st.name, st.setpoint);
+            }
+            if !(0.0..365.0).contains(&st.days) {
+                bail!("Step '{}' has a bad length", st.name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Progress from gravity readings since the batch started
+    pub fn progress(&self, gravities: &Seq, stable_days: f32) -> Progress {
+        let mut g = gravities.clone();
+        g.sort_by_key(|(t, _)| *t);
+
+        let Some(gravity) = mean_before(&g, None) else {
+            return Progress {
+                gravity: None,
+                attenuation: None,
+                abv: None,
+                per_day: None,
+                state: State::Waiting,
+            }
+        };
+
+        let drop = self.og - gravity;
+        let attenuation = Some(drop / (self.og - 1.0));
+        let abv = Some(drop * 131.25);
+
+        // over the last day
+        let last = g.last().map(|(t, _)| *t).unwrap_or_default();
+        let day: Vec<_> = g.iter().filter(|(t, _)| *t >= last - Duration::days(1)).cloned().collect();
+        let per_day = slope_per_day(&day);
+
+        let stable_span = Duration::seconds((stable_days * 86400.0) as i64);
+        let stable = match mean_before(&g, Some(last - stable_span)) {
+            Some(earlier) if g[0].0 <= last - stable_span => (earlier - gravity).abs() < STABLE_CHANGE,
+            _ => false,
+        };
+
+        let state = if !stable {
+            State::Fermenting
+        } else if self.target_fg.map(|fg| gravity <= fg + FG_MARGIN).unwrap_or(true) {
+            State::Finished
+        } else {
+            State::Stalled
+        };
+
+        Progress {
+            gravity: Some(gravity),
+            attenuation,
+            abv,
+            per_day,
+            state,
+        }
+    }
+}
+
+/// Mean of the readings in the hour up to `end`, or up to the latest reading.
+/// iSpindel readings are noisy.
+fn mean_before(g: &[(DateTime<Utc>, f32)], end: Option<DateTime<Utc>>) -> Option<f32> {
+    let end = match end {
+        Some(e) => e,
+        None => g.last()?.0,
+    };
+    let v: Vec<f32> = g.iter()
+        .filter(|(t, _)| *t <= end && *t > end - Duration::hours(1))
+        .map(|(_, v)| *v)
+        .collect();
+    if v.is_empty() {
+        // nothing recent, use the closest earlier one
+        return g.iter().rev().find(|(t, _)| *t <= end).map(|(_, v)| *v)
+    }
+    Some(v.iter().sum::<f32>() / v.len() as f32)
+}
+
+/// Least squares slope, needs at least 6 hours of readings
+fn slope_per_day(g: &[(DateTime<Utc>, f32)]) -> Option<f32> {
+    let (first, last) = (g.first()?.0, g.last()?.0);
+    if last - first < Duration::hours(6) {
+        return None
+    }
+    // days since the first point
+    let xs: Vec<f64> = g.iter().map(|(t, _)| (*t - first).num_seconds() as f64 / 86400.0).collect();
+    let ys: Vec<f64> = g.iter().map(|(_, v)| *v as f64).collect();
+    let n = xs.len() as f64;
+    let (mx, my) = (xs.iter().sum::<f64>() / n, ys.iter().sum::<f64>() / n);
+    let num: f64 = xs.iter().zip(&ys).map(|(x, y)| (x - mx) * (y - my)).sum();
+    let den: f64 = xs.iter().map(|x| (x - mx) * (x - mx)).sum();
+    if den == 0.0 {
+        return None
+    }
+    Some((num / den) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let t = |h: i64| start + Duration::hours(h);
+        let b = Batch {
+            name: "Pale".into(),
+            og: 1.050,
+            target_fg: Some(1.010),
+            started: start.timestamp(),
+            gravity_series: "blue.gravity".into(),
+            style: None,
+            yeast: None,
+            profile: vec![],
+            step: 0,
+            step_started: start.timestamp(),
+        };
+        assert_eq!(b.progress(&vec![], 2.0).state, State::Waiting);
+
+        // dropping 0.010 a day
+        let g: Seq = (0..48).map(|h| (t(h), 1.050 - 0.010 * h as f32 / 24.0)).collect();
+        let p = b.progress(&g, 2.0);
+        assert_eq!(p.state, State::Fermenting);
+        assert!((p.per_day.unwrap() + 0.010).abs() < 0.0005);
+        assert!((p.gravity.unwrap() - 1.0304).abs() < 0.0005);
+        assert!((p.attenuation.unwrap() - 0.39).abs() < 0.01);
+        assert!((p.abv.unwrap() - 2.6).abs() < 0.1);
+
+        // then flat for 3 days at 1.012
+        let mut g2 = g.clone();
+        g2.extend((48..120).map(|h| (t(h), 1.012)));
+        assert_eq!(b.progress(&g2, 2.0).state, State::Finished);
+
+        // flat at 1.025 is short of the target
+        let mut g3 = g;
+        g3.extend((48..120).map(|h| (t(h), 1.025)));
+        assert_eq!(b.progress(&g3, 2.0).state, State::Stalled);
+
+        let mut bad = b.clone();
+        bad.og = 50.0;
+        assert!(bad.check().is_err());
+        assert!(b.check().is_ok());
+    }
+
+    #[test]
+    fn profile() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let t = |h: i64| start + Duration::hours(h);
+        let mut primary = Step::new("Primary", 18.0, 2.0);
+        primary.until_attenuation = Some(50.0);
+        let mut b = Batch {
+            name: "Lager".into(),
+            og: 1.050,
+            target_fg: None,
+            started: start.timestamp(),
+            gravity_series: String::new(),
+            style: None,
+            yeast: None,
+            profile: vec![primary, Step::new("Rest", 20.0, 1.0), Step::new("Crash", 2.0, 3.0)],
+            step: 0,
+            step_started: start.timestamp(),
+        };
+        assert!(!b.advance(t(24), Some(0.3)));
+        // attenuation reached before the time is up
+        assert!(b.advance(t(30), Some(0.5)));
+        assert_eq!(b.current_step().unwrap().name, "Rest");
+        assert_eq!(b.step_end(), Some(t(54)));
+        assert!(!b.advance(t(53), None));
+        assert!(b.advance(t(54), None));
+        // the last step holds
+        assert_eq!(b.step_end(), None);
+        assert!(!b.advance(t(1000), Some(1.0)));
+        assert_eq!(b.current_step().unwrap().setpoint, 2.0);
+
+        b.profile[2].setpoint = 80.0;
+        assert!(b.check().is_err());
+    }
+}
@@ -1,9 +1,15 @@
-use anyhow::{anyhow, Error, Result};
-use serde::Deserialize;
-use std::collections::HashSet;
+use anyhow::{anyhow, bail, Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 
-#[derive(Deserialize)]
+use crate::filter::FilterConfig;
+use crate::ispindel::GravityUnits;
+use crate::output::{OutputConfig, OutputKind};
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Config {
     // all these config options need to be set in default.toml
     pub fridge_delay: u64,
@@ -11,11 +17,37 @@ pub struct Config {
     pub overshoot_interval: u64,
 
     pub sensor_base_dir: String,
+    // optional, bus master directories. Otherwise found in sensor_base_dir
+    #[serde(default)]
+    pub sensor_buses: Vec<String>,
+    // optional, 9 to 12 bits. Lower is faster but coarser
+    #[serde(default)]
+    pub sensor_resolution: Option<u8>,
     pub fridge_gpio_pin: u32,
 
+    // initial sensor aliases, later changed on the sensors page
     pub fridge_name: String,
     pub wort_name: String,
 
+    // optional, keyed by sensor id
+    #[serde(default)]
+    pub calibration: HashMap<String, Calibration>,
+    // optional, disabled by default
+    #[serde(default)]
+    pub filter: FilterConfig,
+    // optional, /ispindel is disabled without it
+    #[serde(default)]
+    pub ispindel_token: Option<String>,
+    // optional, sg by default
+    #[serde(default)]
+    pub ispindel_gravity: GravityUnits,
+    // optional, the gpio line by default
+    #[serde(default)]
+    pub output: OutputConfig,
+    // optional, such as /dev/watchdog
+    #[serde(default)]
+    pub watchdog_device: Option<String>,
+
     pub listen: Vec<String>,
     pub ssl_domain: Vec<String>,
     pub owner_email: String,
@@ -23,13 +55,22 @@ pub struct Config {
     // TODO move this outside
     #[serde(skip_serializing)]
     pub session_secret: String,
-    pub allowed_sessions: HashSet<String>,
+    pub allowed_sessions: BTreeSet<String>,
 
     // defaulted to "."
     pub params_dir: PathBuf,
 
     // hardcoded params, set in Config::default()
     pub sensor_interval: u64,
+    pub history_days: i64,
+    pub sensor_families: Vec<String>,
+    pub sensor_reader: SensorReader,
+    pub sensor_timeout: u64,
+    pub sensor_max_age: u64,
+    pub ispindel_max_age: u64,
+    pub batch_stable_days: f32,
+    pub stuck_relay_time: u64,
+    pub supervisor_timeout: u64,
 
     // runtime parameters usually from the command line
     // need to be set in Config::default()
@@ -40,11 +81,59 @@ pub struct Config {
     pub testssl: bool,
 }
 
+/// Which sysfs file 1-wire temperatures are read from
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorReader {
+    /// Just the temperature
+    Temperature,
+    /// The scratchpad with a CRC, so bad reads can be told apart
+    W1Slave,
+}
+
+/// Correction for a sensor's raw reading. A two-point correction uses readings
+/// from an ice bath and boiling water, then `offset` is added.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Calibration {
+    #[serde(default)]
+    pub offset: f32,
+    /// Raw reading in an ice bath
+    pub ice: Option<f32>,
+    /// Raw reading in boiling water
+    pub boil: Option<f32>,
+    /// The real boiling point, lower at altitude. Defaults to 100°
+    pub boil_actual: Option<f32>,
+}
+
+impl Calibration {
+    pub fn apply(&self, raw: f32) -> f32 {
+        let t = match (self.ice, self.boil) {
+            (Some(ice), Some(boil)) if boil > ice => {
+                let boil_actual = self.boil_actual.unwrap_or(100.0);
+                (raw - ice) * boil_actual / (boil - ice)
+            }
+            _ => raw,
+        };
+        t + self.offset
+    }
+}
+
 impl Config {
     pub fn example_toml() -> &'static str {
         include_str!("defconfig.toml")
     }
 
+    /// The example config loaded, for tests
+    #[cfg(test)]
+    pub fn example() -> Self {
+        let p = std::env::temp_dir().join(format!("fridgyeast-test-{}-{:?}.toml",
+            std::process::id(), std::thread::current().id()));
+        std::fs::write(&p, Config::example_toml()).unwrap();
+        let c = Config::load(&p.to_string_lossy()).unwrap();
+        std::fs::remove_file(&p).unwrap();
+        c
+    }
+
     pub fn load(conf_file: &str) -> Result<Self> {
         let c = config::Config::builder()
         // defaults for args
@@ -55,6 +144,16 @@ impl Config {
         .set_default("testssl", false)?
         // hidden config, not in defconfig.toml
         .set_default("sensor_interval", 10)? // 10 seconds
+        .set_default("history_days", 14)?
+        // ds18b20, ds18s20, ds1822
+        .set_default("sensor_families", vec!["28", "10", "22"])?
+        .set_default("sensor_reader", "temperature")?
+        .set_default("sensor_timeout", 5)? // seconds for each sensor read
+        .set_default("sensor_max_age", 60)? // older readings are ignored
+        .set_default("ispindel_max_age", 1800)? // they report every 15 minutes or so
+        .set_default("batch_stable_days", 2.0)? // finished or stalled once gravity is steady
+        .set_default("stuck_relay_time", 1800)? // seconds on or off before the relay is judged, 0 disables
+        .set_default("supervisor_timeout", 60)? // seconds without a control loop tick before turning off, 0 disables
         .set_default("params_dir", ".")?
         .add_source(config::File::with_name(conf_file))
         .add_source(config::Environment::with_prefix("TEMPLOG"))
@@ -68,6 +167,208 @@ impl Config {
         let conf: Self = c
             .try_deserialize()
             .map_err(|e| Error::new(e).context(format!("Problem loading config {}", conf_file)))?;
+        conf.check().map_err(|e| e.context(format!("Problem in config {}", conf_file)))?;
         Ok(conf)
     }
+
+    /// Catches mistakes that would otherwise only show up later
+    fn check(&self) -> Result<()> {
+        if self.listen.is_empty() {
+            bail!("listen is empty");
+        }
+        if self.overshoot_interval == 0 || self.sensor_interval == 0 {
+            bail!("overshoot_interval and sensor_interval can't be 0");
+        }
+        if let Some(r) = self.sensor_resolution {
+            if !(9..=12).contains(&r) {
+                bail!("sensor_resolution {r} should be 9 to 12");
+            }
+        }
+        self.filter.check()?;
+        if self.output.kind != OutputKind::Gpio && self.output.host.is_none() {
+            bail!("output.host is needed for {:?}", self.output.kind);
+        }
+        Ok(())
+    }
+
+    /// Copies the settings in `LIVE` from `new`, the rest need a restart
+    pub fn take_live(&mut self, new: &Config) {
+        let mut cur = to_map(self);
+        let new = to_map(new);
+        for key in LIVE {
+            match key.split_once('.') {
+                Some((table, k)) => {
+                    let v = new[table][k].clone();
+                    cur.get_mut(table).and_then(Value::as_object_mut)
+                        .expect("LIVE table is in Config")
+                        .insert(k.into(), v);
+                }
+                None => {
+                    cur.insert(key.to_string(), new[*key].clone());
+                }
+            }
+        }
+        // not serialized
+        cur.insert("session_secret".into(), self.session_secret.clone().into());
+        *self = serde_json::from_value(Value::Object(cur)).expect("Config round trips");
+    }
+}
+
+/// Settings that `Config::take_live()` applies without a restart, including
+/// everything below a table such as `calibration`
+const LIVE: &[&str] = &[
+    "fridge_delay", "fridge_wort_invalid_time", "overshoot_interval",
+    "sensor_base_dir", "sensor_buses", "sensor_resolution", "fridge_name", "wort_name",
+    "calibration", "filter", "ispindel_token", "ispindel_gravity",
+    "output.readback_interval",
+    "owner_email", "allowed_sessions",
+    "sensor_interval", "sensor_families", "sensor_reader", "sensor_timeout",
+    "sensor_max_age", "ispindel_max_age", "batch_stable_days", "stuck_relay_time",
+];
+
+/// A setting that differs between two configs, values are shown as json
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// Such as `filter.median`
+    pub key: String,
+    pub old: String,
+    pub new: String,
+}
+
+impl Change {
+    pub fn is_live(&self) -> bool {
+        LIVE.iter().any(|l| self.is_under(l))
+    }
+
+    /// `key` is `prefix` or inside it
+    pub fn is_under(&self, prefix: &str) -> bool {
+        self.key.strip_prefix(prefix).is_some_and(|r| r.is_empty() || r.starts_with('.'))
+    }
+}
+
+/// Differences between `old` and `new`, flattened to dotted keys
+pub fn diff(old: &Config, new: &Config) -> Vec<Change> {
+    let (old_flat, new_flat) = (flatten(old), flatten(new));
+    let keys: BTreeSet<&String> = old_flat.keys().chain(new_flat.keys()).collect();
+    let show = |v: Option<&Value>| v.map(|v| v.to_string()).unwrap_or("(none)".into());
+    let mut changes: Vec<Change> = keys.into_iter()
+        .filter(|k| old_flat.get(*k) != new_flat.get(*k))
+        .map(|k| Change { key: k.clone(), old: show(old_flat.get(k)), new: show(new_flat.get(k)) })
+        .collect();
+    // not serialized
+    if old.session_secret != new.session_secret {
+        changes.push(Change { key: "session_secret".into(), old: "(hidden)".into(), new: "(hidden)".into() });
+    }
+    changes
+}
+
+/// Top level settings as json
+fn to_map(c: &Config) -> serde_json::Map<String, Value> {
+    // via a string so that f32 values print as written
+    let s = serde_json::to_string(c).expect("Config serializes");
+    let Value::Object(top) = serde_json::from_str(&s).expect("Config serializes") else {
+        unreachable!("Config is a struct")
+    };
+    top
+}
+
+/// Tables such as `filter` are split into their entries, one level deep
+fn flatten(c: &Config) -> BTreeMap<String, Value> {
+    let mut out = BTreeMap::new();
+    for (k, v) in to_map(c) {
+        match v {
+            Value::Object(table) => {
+                out.extend(table.into_iter().map(|(tk, tv)| (format!("{k}.{tk}"), tv)));
+            }
+            v => {
+                out.insert(k, v);
+            }
+        }
+    }
+    out
+}
+
+/// The current config, replaced by a reload. Replaced configs are leaked
+/// since references to them can be held anywhere, they're small.
+#[derive(Clone)]
+pub struct Live(Arc<RwLock<&'static Config>>);
+
+impl Live {
+    pub fn new(config: &'static Config) -> Self {
+        Live(Arc::new(RwLock::new(config)))
+    }
+
+    pub fn get(&self) -> &'static Config {
+        *self.0.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set(&self, config: &'static Config) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = config;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibration() {
+        let c = Calibration { offset: -0.5, ..Default::default() };
+        assert_eq!(c.apply(20.0), 19.5);
+
+        let c = Calibration { ice: Some(0.5), boil: Some(98.5), ..Default::default() };
+        assert_eq!(c.apply(0.5), 0.0);
+        assert_eq!(c.apply(98.5), 100.0);
+        assert_eq!(c.apply(49.5), 50.0);
+
+        let c = Calibration { ice: Some(0.0), boil: Some(99.0), boil_actual: Some(99.0),
+            offset: 0.1 };
+        assert_eq!(c.apply(20.0), 20.1);
+    }
+
+    #[test]
+    fn reload() {
+        let old = Config::example();
+        assert!(diff(&old, &old.clone()).is_empty());
+
+        let mut new = old.clone();
+        new.fridge_delay += 60;
+        new.filter.smoothing = 0.3;
+        new.calibration.insert("28-aa".into(), Calibration { offset: -0.2, ..Default::default() });
+        new.output.readback_interval = 10;
+        new.output.timeout = 9;
+        new.history_days = 2;
+        new.session_secret = "another".into();
+        let changes = diff(&old, &new);
+        let keys: Vec<_> = changes.iter().map(|c| c.key.as_str()).collect();
+        assert_eq!(keys, ["calibration.28-aa", "filter.smoothing", "fridge_delay", "history_days",
+            "output.readback_interval", "output.timeout", "session_secret"]);
+        assert_eq!(changes[1].old, "1.0");
+        assert_eq!(changes[1].new, "0.3");
+        assert_eq!(changes[0].old, "(none)");
+
+        // what isn't taken needs a restart
+        let mut applied = old.clone();
+        applied.take_live(&new);
+        let restart: Vec<_> = diff(&applied, &new).into_iter().map(|c| c.key).collect();
+        assert_eq!(restart, ["history_days", "output.timeout", "session_secret"]);
+        for c in changes {
+            assert_eq!(c.is_live(), !restart.contains(&c.key), "{}", c.key);
+        }
+        // each LIVE key is a setting
+        let map = to_map(&old);
+        for l in LIVE {
+            let found = match l.split_once('.') {
+                Some((table, k)) => map.get(table).and_then(|t| t.get(k)).is_some(),
+                None => map.contains_key(*l),
+            };
+            assert!(found, "{l}");
+        }
+        assert!(Change { key: "filter".into(), old: "".into(), new: "".into() }.is_under("filter"));
+        assert!(!Change { key: "filters".into(), old: "".into(), new: "".into() }.is_under("filter"));
+
+        let mut bad = old.clone();
+        bad.sensor_resolution = Some(8);
+        assert!(bad.check().is_err());
+    }
 }
@@ -0,0 +1,215 @@
+//! Formats stored history for download from `/export`
+
+#[allow(unused_imports)]
+use {
+    log::{debug, error, info, warn},
+    anyhow::{anyhow,Result,Context,bail},
+};
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use chrono::{DateTime,offset::Utc};
+use serde::Serialize;
+
+use crate::timeseries::{Event, ExportRow};
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Format {
+    Csv,
+    Json,
+    /// InfluxDB line protocol, nanosecond timestamps
+    Line,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            "line" | "influx" => Ok(Format::Line),
+            _ => bail!("Unknown export format '{s}'"),
+        }
+    }
+}
+
+impl Format {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv",
+            Format::Json => "application/json",
+            Format::Line => "text/plain",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Json => "json",
+            Format::Line => "txt",
+        }
+    }
+
+    pub fn write(&self, rows: &[ExportRow]) -> Result<String> {
+        match self {
+            Format::Csv => Ok(csv(rows)),
+            Format::Json => json(rows),
+            Format::Line => Ok(line_protocol(rows)),
+        }
+    }
+
+    pub fn write_events(&self, events: &[Event]) -> Result<String> {
+        match self {
+            Format::Csv => Ok(csv_events(events)),
+            Format::Json => json_events(events),
+            Format::Line => Ok(line_protocol_events(events)),
+        }
+    }
+}
+
+/// Parses a time given either as unix seconds or RFC3339
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>> {
+    if let Ok(secs) = s.parse::<i64>() {
+        return DateTime::from_timestamp(secs, 0).ok_or_else(|| anyhow!("Bad timestamp {s}"))
+    }
+    let t = DateTime::parse_from_rfc3339(s).with_context(|| format!("Bad time '{s}'"))?;
+    Ok(t.with_timezone(&Utc))
+}
+
+fn csv_quote(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn csv(rows: &[ExportRow]) -> String {
+    let mut out = String::from("time,timestamp,name,value,count\n");
+    for r in rows {
+        let count = r.count.map(|c| c.to_string()).unwrap_or_default();
+        // series names are plain, but quote anything odd
+        let name = csv_quote(&r.name);
+        writeln!(out, "{},{},{},{},{}", r.time.to_rfc3339(), r.time.timestamp(),
+            name, r.value, count).unwrap();
+    }
+    out
+}
+
+fn json(rows: &[ExportRow]) -> Result<String> {
+    // a struct rather than json!() so that value is serialized as f32
+    #[derive(Serialize)]
+    struct JsonRow<'a> {
+        time: String,
+        timestamp: i64,
+        name: &'a str,
+        value: f32,
+        count: Option<u32>,
+    }
+
+    let v: Vec<JsonRow> = rows.iter().map(|r| {
+        JsonRow {
+            time: r.time.to_rfc3339(),
+            timestamp: r.time.timestamp(),
+            name: &r.name,
+            value: r.value,
+            count: r.count,
+        }
+    }).collect();
+    Ok(serde_json::to_string(&v)?)
+}
+
+/// Escapes a line protocol tag value
+fn line_tag(s: &str) -> String {
+    s.replace(' ', "\\ ").replace(',', "\\,").replace('=', "\\=")
+}
+
+fn line_protocol(rows: &[ExportRow]) -> String {
+    let mut out = String::new();
+    for r in rows {
+        let name = line_tag(&r.name);
+        let count = r.count.map(|c| format!(",count={c}i")).unwrap_or_default();
+        let nanos = r.time.timestamp() as i128 * 1_000_000_000;
+        writeln!(out, "fridgyeast,series={name} value={}{count} {nanos}", r.value).unwrap();
+    }
+    out
+}
+
+fn csv_events(events: &[Event]) -> String {
+    let mut out = String::from("time,timestamp,kind,note\n");
+    for e in events {
+        writeln!(out, "{},{},{},{}", e.time.to_rfc3339(), e.time.timestamp(),
+            csv_quote(&e.kind), csv_quote(&e.note)).unwrap();
+    }
+    out
+}
+
+fn json_events(events: &[Event]) -> Result<String> {
+    let v: Vec<serde_json::Value> = events.iter().map(|e| {
+        serde_json::json!({
+            "time": e.time.to_rfc3339(),
+            "timestamp": e.time.timestamp(),
+            "kind": e.kind,
+            "note": e.note,
+        })
+    }).collect();
+    Ok(serde_json::to_string(&v)?)
+}
+
+fn line_protocol_events(events: &[Event]) -> String {
+    let mut out = String::new();
+    for e in events {
+        let kind = line_tag(&e.kind);
+        let note = e.note.replace('\\', "\\\\").replace('"', "\\\"");
+        let nanos = e.time.timestamp() as i128 * 1_000_000_000;
+        writeln!(out, "fridgyeast_event,kind={kind} note=\"{note}\" {nanos}").unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows() -> Vec<ExportRow> {
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        vec![
+            ExportRow { time: t, name: "wort".into(), value: 18.5, count: Some(3) },
+            ExportRow { time: t, name: "setpoint".into(), value: 18.0, count: None },
+        ]
+    }
+
+    #[test]
+    fn formats() {
+        let c = Format::Csv.write(&rows()).unwrap();
+        assert_eq!(c, "time,timestamp,name,value,count\n\
+            2023-11-14T22:13:20+00:00,1700000000,wort,18.5,3\n\
+            2023-11-14T22:13:20+00:00,1700000000,setpoint,18,\n");
+
+        let l = Format::Line.write(&rows()).unwrap();
+        assert_eq!(l, "fridgyeast,series=wort value=18.5,count=3i 1700000000000000000\n\
+            fridgyeast,series=setpoint value=18 1700000000000000000\n");
+
+        let j: serde_json::Value = serde_json::from_str(&Format::Json.write(&rows()).unwrap()).unwrap();
+        assert_eq!(j[0]["count"], 3);
+        assert!(j[1]["count"].is_null());
+    }
+
+    #[test]
+    fn events() {
+        let t = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let ev = vec![Event { time: t, kind: "dry hop".into(), note: "50g \"Citra\", 20g Mosaic".into() }];
+        assert_eq!(Format::Csv.write_events(&ev).unwrap(), "time,timestamp,kind,note\n\
+            2023-11-14T22:13:20+00:00,1700000000,dry hop,\"50g \"\"Citra\"\", 20g Mosaic\"\n");
+        assert_eq!(Format::Line.write_events(&ev).unwrap(),
+            "fridgyeast_event,kind=dry\\ hop note=\"50g \\\"Citra\\\", 20g Mosaic\" 1700000000000000000\n");
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("1700000000").unwrap().timestamp(), 1700000000);
+        assert_eq!(parse_time("2023-11-15T09:13:20+11:00").unwrap().timestamp(), 1700000000);
+        assert!(parse_time("yesterday").is_err());
+    }
+}
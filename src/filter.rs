@@ -0,0 +1,249 @@
+//! Outlier rejection and smoothing for readings before `Fridge` acts on them
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::Instant;
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Quality, Readings};
+
+/// After this many fast changing samples in a row the new value is accepted,
+/// it was probably a real change like a probe being moved.
+const REJECT_RESET: u32 = 3;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// Median of this many recent samples, 1 disables it
+    pub median: usize,
+    /// Samples changing faster than this many degrees per minute are rejected
+    pub max_rate: Option<f32>,
+    /// Exponential smoothing weight for a new sample, 1.0 disables it
+    pub smoothing: f32,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            median: 1,
+            max_rate: None,
+            smoothing: 1.0,
+        }
+    }
+}
+
+impl FilterConfig {
+    /// A smoothing of 0 would freeze readings at their first value
+    pub fn check(&self) -> Result<()> {
+        if self.median == 0 {
+            bail!("filter.median should be at least 1");
+        }
+        if !(self.smoothing > 0.0 && self.smoothing <= 1.0) {
+            bail!("filter.smoothing {} should be above 0, up to 1", self.smoothing);
+        }
+        if self.max_rate.is_some_and(|r| r.is_nan() || r <= 0.0) {
+            bail!("filter.max_rate should be above 0");
+        }
+        Ok(())
+    }
+
+    pub fn active(&self) -> bool {
+        self.median > 1 || self.max_rate.is_some() || self.smoothing < 1.0
+    }
+}
+
+/// Filter state for one sensor
+pub struct SensorFilter {
+    recent: VecDeque<f32>,
+    /// The last accepted sample
+    last: Option<(Instant, f32)>,
+    /// Latest output
+    out: Option<f32>,
+    /// Reading time of the latest accepted sample, for held values
+    out_time: Option<DateTime<Utc>>,
+    rejected_run: u32,
+    pub rejected: u64,
+}
+
+impl SensorFilter {
+    pub fn new() -> Self {
+        SensorFilter {
+            recent: VecDeque::new(),
+            last: None,
+            out: None,
+            out_time: None,
+            rejected_run: 0,
+            rejected: 0,
+        }
+    }
+
+    /// Returns the filtered value, or `None` if `v` was rejected and there is no
+    /// earlier value.
+    pub fn add(&mut self, conf: &FilterConfig, now: Instant, v: f32) -> Option<f32> {
+        if let (Some(max_rate), Some((t0, v0))) = (conf.max_rate, self.last) {
+            // at least a second, readings can arrive close together
+            let mins = (now - t0).as_secs_f32().max(1.0) / 60.0;
+            let rate = (v - v0).abs() / mins;
+            if rate > max_rate {
+                if self.rejected_run + 1 < REJECT_RESET {
+                    self.rejected += 1;
+                    self.rejected_run += 1;
+                    info!("Rejected {v}°, {rate:.1}°/min from {v0}°");
+                    return self.out;
+                }
+                info!("Accepting {v}° after {} rejected samples", self.rejected_run);
+                // start afresh from the new level
+                self.recent.clear();
+                self.out = None;
+            }
+        }
+        self.rejected_run = 0;
+        self.last = Some((now, v));
+
+        self.recent.push_back(v);
+        while self.recent.len() > conf.median {
+            self.recent.pop_front();
+        }
+        let m = median(&self.recent);
+
+        let out = match self.out {
+            Some(prev) => prev + conf.smoothing * (m - prev),
+            None => m,
+        };
+        self.out = Some(out);
+        Some(out)
+    }
+}
+
+fn median(v: &VecDeque<f32>) -> f32 {
+    let mut s: Vec<f32> = v.iter().copied().collect();
+    s.sort_by(f32::total_cmp);
+    let n = s.len();
+    if n % 2 == 1 {
+        s[n / 2]
+    } else {
+        (s[n / 2 - 1] + s[n / 2]) / 2.0
+    }
+}
+
+/// Filters for all sensors, by name
+pub struct Filters {
+    conf: FilterConfig,
+    sensors: HashMap<String, SensorFilter>,
+}
+
+impl Filters {
+    pub fn new(conf: &FilterConfig) -> Self {
+        Filters {
+            conf: conf.clone(),
+            sensors: HashMap::new(),
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.conf.active()
+    }
+
+    /// Replaces temperatures with filtered values
+    pub fn apply(&mut self, r: &mut Readings, now: Instant) {
+        if !self.active() {
+            return
+        }
+        r.temps.retain(|name, reading| {
+            let f = self.sensors.entry(name.clone()).or_insert_with(SensorFilter::new);
+            let before = f.rejected;
+            match f.add(&self.conf, now, reading.temp) {
+                Some(t) => {
+                    reading.temp = t;
+                    if f.rejected > before {
+                        // as old as the value being held, so it still goes stale
+                        reading.quality = Quality::Held;
+                        if let Some(t) = f.out_time {
+                            reading.time = t;
+                        }
+                    } else {
+                        f.out_time = Some(reading.time);
+                    }
+                    true
+                }
+                None => false,
+            }
+        });
+    }
+
+    /// Rejected sample counts
+    pub fn rejected(&self) -> BTreeMap<String, u64> {
+        self.sensors.iter()
+            .filter(|(_, f)| f.rejected > 0)
+            .map(|(n, f)| (n.clone(), f.rejected))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::types::{Reading, Source};
+
+    #[test]
+    fn filter() {
+        let t0 = Instant::now();
+        let t = |s: u64| t0 + Duration::from_secs(s);
+
+        let conf = FilterConfig { median: 3, max_rate: Some(1.0), smoothing: 1.0 };
+        let mut f = SensorFilter::new();
+        assert_eq!(f.add(&conf, t(0), 18.0), Some(18.0));
+        assert_eq!(f.add(&conf, t(60), 18.5), Some(18.25));
+        // a glitch is rejected, keeping the last value
+        assert_eq!(f.add(&conf, t(120), 85.0), Some(18.25));
+        assert_eq!(f.rejected, 1);
+        assert_eq!(f.add(&conf, t(180), 18.75), Some(18.5));
+        assert_eq!(f.add(&conf, t(240), 19.0), Some(18.75));
+
+        // a real step change is accepted after a few
+        assert_eq!(f.add(&conf, t(300), 30.0), Some(18.75));
+        assert_eq!(f.add(&conf, t(360), 30.0), Some(18.75));
+        assert_eq!(f.add(&conf, t(420), 30.25), Some(30.25));
+        assert_eq!(f.rejected, 3);
+
+        let conf = FilterConfig { smoothing: 0.5, ..Default::default() };
+        let mut f = SensorFilter::new();
+        assert_eq!(f.add(&conf, t(0), 10.0), Some(10.0));
+        assert_eq!(f.add(&conf, t(10), 20.0), Some(15.0));
+        assert_eq!(f.add(&conf, t(20), 20.0), Some(17.5));
+
+        // nothing accepted yet
+        let conf = FilterConfig { max_rate: Some(1.0), ..Default::default() };
+        let mut f = SensorFilter::new();
+        f.last = Some((t(0), 5.0));
+        assert_eq!(f.add(&conf, t(10), 50.0), None);
+
+        // held readings keep the time of the value they hold
+        let mut fs = Filters::new(&FilterConfig { max_rate: Some(1.0), ..Default::default() });
+        let mut r = Readings::new();
+        r.add("wort", Reading::new(18.0, Source::Test));
+        let first = r.temps["wort"].time;
+        fs.apply(&mut r, t(0));
+        let mut r = Readings::new();
+        let mut glitch = Reading::new(85.0, Source::Test);
+        glitch.time = first + chrono::Duration::seconds(60);
+        r.add("wort", glitch);
+        fs.apply(&mut r, t(60));
+        assert_eq!(r.temps["wort"].temp, 18.0);
+        assert_eq!(r.temps["wort"].quality, Quality::Held);
+        assert_eq!(r.temps["wort"].time, first);
+
+        assert!(FilterConfig::default().check().is_ok());
+        assert!(FilterConfig { median: 0, ..Default::default() }.check().is_err());
+        assert!(FilterConfig { smoothing: 0.0, ..Default::default() }.check().is_err());
+        assert!(FilterConfig { smoothing: 1.5, ..Default::default() }.check().is_err());
+        assert!(FilterConfig { smoothing: f32::NAN, ..Default::default() }.check().is_err());
+        assert!(FilterConfig { max_rate: Some(0.0), ..Default::default() }.check().is_err());
+    }
+}
@@ -7,6 +7,8 @@ use {
 use crate::actzero_pubsub::Subscriber;
 use async_std::task::block_on;
 use async_trait::async_trait;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use act_zero::runtimes::async_std::spawn_actor;
@@ -19,19 +21,48 @@ use serde_json::ser::to_string_pretty;
 
 use chrono::{offset::Utc, DateTime};
 
-use super::config::Config;
-use crate::params::Params;
+use super::config::{self, Config};
+use crate::params::{ManualMode, ManualOverride, Params};
+use crate::aliases::{Aliases, SensorInfo};
+use crate::filter::Filters;
+use crate::ispindel;
+use crate::batch::{self, Batch, Progress};
 
+use super::output::{self, onoff, SharedOutput, Stuck, StuckDetector};
+use super::sdnotify::SdNotify;
+use super::supervisor::Heartbeat;
 use super::sensor;
-use super::timeseries::{Seq, TimeSeries};
+use super::timeseries::{Event, ExportRow, Seq, TimeSeries};
 use super::types::*;
 
+/// Seconds, points in a series are averaged over this time
+const TIMESERIES_QUANTISE: u64 = 300;
+/// An iSpindel that hasn't reported for this long is forgotten
+const ISPINDEL_FORGET: Duration = Duration::from_secs(24 * 3600);
+
+/// Stepwise series recorded by `Fridge` itself
+pub const SETPOINT_SERIES: &str = "setpoint";
+pub const RUNNING_SERIES: &str = "running";
+pub const COMPRESSOR_SERIES: &str = "compressor";
+/// Names sensors can't be aliased to. "events" is the timeline in exports.
+pub const INTERNAL_SERIES: &[&str] = &[SETPOINT_SERIES, RUNNING_SERIES, COMPRESSOR_SERIES, "events"];
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Status {
     pub params: Params,
     pub on: bool,
     pub temp_wort: Option<f32>,
     pub temp_fridge: Option<f32>,
+    /// Latest readings from all sensors by alias, calibrated and raw
+    pub readings: Readings,
+    /// Seconds since the latest reading from each sensor, by alias
+    pub reading_age: BTreeMap<String, u64>,
+    /// Sensors with readings older than sensor_max_age, or ispindel_max_age
+    pub stale: Vec<String>,
+    /// Samples rejected by the filter, by alias
+    pub rejected: BTreeMap<String, u64>,
+    /// Current problems needing attention, such as a stuck relay
+    pub alarms: BTreeMap<String, String>,
     pub off_duration: Duration,
     pub fridge_delay: Duration,
 
@@ -50,34 +81,51 @@ pub struct Fridge {
     on: bool,
     temp_wort: Option<f32>,
     temp_fridge: Option<f32>,
+    /// The latest reading by alias, which may be stale
+    readings: Readings,
+    aliases: Aliases,
+    filters: Filters,
+    /// By sensor id
+    sensors: BTreeMap<String, SensorInfo>,
     last_off_time: Instant,
     wort_valid_time: Instant,
     integrator: StepIntegrator,
-    output: FridgeOutput,
+    output: SharedOutput,
+    heartbeat: Heartbeat,
+    systemd: Arc<SdNotify>,
+    last_readback: Instant,
+    stuck: StuckDetector,
+    alarms: BTreeMap<String, String>,
     started: Instant,
 
     timer: Timer,
+    /// For restarting the sensor actor
+    addr: WeakAddr<Self>,
 
     // avoid printing logs too often
     often_tooearly: NotTooOften,
     often_badfridge: NotTooOften,
     often_badwort: NotTooOften,
 
+    batch: Option<Batch>,
+    /// The latest gravity series posted, a default for new batches
+    last_gravity_series: Option<String>,
+
+    // for timeline events on changes
+    batch_state: Option<batch::State>,
+    wort_fallback: bool,
+    missing_sensors: BTreeSet<String>,
+
     sensor: Option<Addr<dyn Actor>>,
     timeseries: Addr<TimeSeries>,
 }
 
-enum FridgeOutput {
-    Gpio(gpio_cdev::LineHandle),
-    Fake,
-}
-
 impl Drop for Fridge {
     fn drop(&mut self) {
         if self.on {
             info!("Fridge turns off at shutdown");
         }
-        self.turn_off();
+        block_on(self.turn_off());
 
         // make sure timeseries has flushed to disk
         let t = self.timeseries.termination();
@@ -97,7 +145,13 @@ impl Subscriber<Readings> for Fridge {
 impl Tick for Fridge {
     async fn tick(&mut self) -> ActorResult<()> {
         if self.timer.tick() {
-            self.update();
+            self.heartbeat.beat();
+            self.follow_profile(None).await;
+            self.check_override().await;
+            self.check_output().await;
+            self.check_stuck();
+            self.update().await;
+            self.systemd.status(&self.status_line());
         }
         Produces::ok(())
     }
@@ -113,16 +167,13 @@ impl Actor for Fridge {
             self.last_off_time -= Duration::new(self.config.fridge_delay, 1);
         }
 
-        if self.config.testmode {
-            let sens = sensor::TestSensor::new(self.config, upcast!(addr.downgrade()));
-            self.sensor = Some(upcast!(spawn_actor(sens)));
-        } else {
-            let sens = sensor::OneWireSensor::new(self.config, upcast!(addr.downgrade()));
-            self.sensor = Some(upcast!(spawn_actor(sens)));
-        };
+        self.event("restart", format!("version {}", get_vcs_version()));
+
+        self.addr = addr.downgrade();
+        self.start_sensor();
 
         // Start the timer going
-        self.update();
+        self.update().await;
         // Arbitrary 10 secs, enough to notice invalid wort or fridge delay
         self.timer
             .set_interval_weak(addr.downgrade(), Duration::from_secs(10));
@@ -136,13 +187,13 @@ impl Actor for Fridge {
 }
 
 impl Fridge {
-    pub fn try_new(config: &'static Config) -> Result<Self> {
-        let output = Self::make_output(config)?;
+    pub fn try_new(config: &'static Config, notify: Arc<SdNotify>) -> Result<Self> {
+        let output = output::make(config)?;
 
         let timeseries = spawn_actor(TimeSeries::new(
             std::path::Path::new("fridgyeast.db"),
-            300,
-            chrono::Duration::days(2),
+            TIMESERIES_QUANTISE,
+            chrono::Duration::days(config.history_days),
         )?);
 
         let mut f = Fridge {
@@ -151,46 +202,334 @@ impl Fridge {
             on: false,
             temp_wort: None,
             temp_fridge: None,
+            readings: Readings::new(),
+            aliases: Aliases::load(config)?,
+            filters: Filters::new(&config.filter),
+            sensors: BTreeMap::new(),
             last_off_time: Instant::now(),
             wort_valid_time: Instant::now() - Duration::new(config.fridge_wort_invalid_time, 100),
             integrator: StepIntegrator::new(Duration::from_secs(config.overshoot_interval)),
             output,
+            heartbeat: Heartbeat::new(),
+            systemd: notify,
+            last_readback: Instant::now(),
+            stuck: StuckDetector::new(Duration::from_secs(config.stuck_relay_time), Instant::now()),
+            alarms: BTreeMap::new(),
             often_tooearly: NotTooOften::new(300),
             often_badwort: NotTooOften::new(100),
             often_badfridge: NotTooOften::new(300),
+            batch: Batch::load(config)?,
+            last_gravity_series: None,
+            batch_state: None,
+            wort_fallback: false,
+            missing_sensors: BTreeSet::new(),
             timer: Timer::default(),
+            addr: WeakAddr::detached(),
             started: Instant::now(),
             sensor: None,
             timeseries,
         };
 
-        send!(f.timeseries.add_step("setpoint", f.params.fridge_setpoint));
+        send!(f.timeseries.add_step(SETPOINT_SERIES, f.params.fridge_setpoint));
+        send!(f.timeseries.add_step(RUNNING_SERIES, f.params.running as u8 as f32));
         send!(f.timeseries.save());
 
         // Early check the fridge can turn off
-        f.turn(false).context("Initial fridge turn-off")?;
+        block_on(f.turn(false)).context("Initial fridge turn-off")?;
 
         Ok(f)
     }
 
+    /// Replaces any running sensor actor, which stops once dropped
+    fn start_sensor(&mut self) {
+        if self.config.testmode {
+            let sens = sensor::TestSensor::new(self.config, upcast!(self.addr.clone()),
+                self.systemd.clone());
+            self.sensor = Some(upcast!(spawn_actor(sens)));
+        } else {
+            let sens = sensor::OneWireSensor::new(self.config, upcast!(self.addr.clone()),
+                self.systemd.clone());
+            self.sensor = Some(upcast!(spawn_actor(sens)));
+        };
+    }
+
+    /// Applies a reloaded config. Only settings that `config::Change::is_live()`
+    /// should differ, others are left to a restart.
+    pub async fn set_config(&mut self, config: &'static Config) -> ActorResult<()> {
+        let changes = config::diff(self.config, config);
+        if changes.is_empty() {
+            return Produces::ok(());
+        }
+        self.config = config;
+        let changed = |prefixes: &[&str]| changes.iter()
+            .any(|c| prefixes.iter().any(|p| c.is_under(p)));
+
+        if changed(&["filter"]) {
+            self.filters = Filters::new(&config.filter);
+        }
+        if changed(&["overshoot_interval"]) {
+            self.integrator.set_limit(Duration::from_secs(config.overshoot_interval));
+        }
+        if changed(&["stuck_relay_time"]) {
+            self.stuck.set_time(Duration::from_secs(config.stuck_relay_time));
+        }
+        // same as at startup, the names only seed a missing aliases file
+        if changed(&["wort_name", "fridge_name"]) {
+            warn!("wort_name and fridge_name are only used before {} exists, change sensors on /sensors",
+                Aliases::FILENAME);
+        }
+        if changed(&["sensor_base_dir", "sensor_buses", "sensor_resolution", "sensor_interval",
+            "sensor_families", "sensor_reader", "sensor_timeout", "calibration"]) {
+            info!("Restarting sensors for the new config");
+            self.start_sensor();
+        }
+
+        let keys: Vec<_> = changes.iter().map(|c| c.key.as_str()).collect();
+        self.event("config", format!("reloaded {}", keys.join(", ")));
+        self.update().await;
+        Produces::ok(())
+    }
+
+    /// For the supervisor to watch the control loop, and turn the output off if it stops
+    pub fn supervision(&self) -> (Heartbeat, SharedOutput) {
+        (self.heartbeat.clone(), self.output.clone())
+    }
+
     pub async fn add_readings(&mut self, r: Readings) {
         debug!("add_readings {r:?}");
-        self.temp_wort = r.get_temp(&self.config.wort_name);
-        self.temp_fridge = r.get_temp(&self.config.fridge_name);
+        for (id, reading) in &r.temps {
+            let s = self.sensors.entry(id.clone()).or_insert_with(|| SensorInfo::new(id));
+            s.temp = Some(reading.temp);
+            s.raw = Some(reading.raw);
+            s.last_seen = Some(reading.time);
+            s.latency = Some(reading.latency);
+        }
+        for id in &r.failed {
+            self.sensors.entry(id.clone()).or_insert_with(|| SensorInfo::new(id)).errors += 1;
+        }
+
+        let mut r = self.aliases.apply(&r);
+        // every sensor found on the bus, before filtering drops any
+        let found: HashSet<String> = r.temps.keys().chain(&r.failed).cloned().collect();
+        if self.filters.active() {
+            for (name, reading) in &r.temps {
+                send!(self.timeseries.add(format!("{name}.unfiltered"), reading.temp));
+            }
+            self.filters.apply(&mut r, Instant::now());
+        }
+
+        for (name, reading) in &r.temps {
+            send!(self.timeseries.add(name.clone(), reading.temp));
+        }
+
+        let from_sensors = r.temps.values().any(|r| r.source != Source::ISpindel)
+            || !r.failed.is_empty();
+        self.readings.temps.extend(r.temps);
+        if from_sensors {
+            self.readings.failed = r.failed;
+            // drop sensors that were renamed or removed
+            let now = Utc::now();
+            self.readings.temps.retain(|name, r| match r.source {
+                Source::ISpindel => r.age(now) < ISPINDEL_FORGET,
+                _ => found.contains(name),
+            });
+        }
+        self.update().await;
+    }
+
+    /// Readings older than this are ignored
+    fn max_age(&self, source: Source) -> Duration {
+        match source {
+            Source::ISpindel => Duration::from_secs(self.config.ispindel_max_age),
+            _ => Duration::from_secs(self.config.sensor_max_age),
+        }
+    }
+
+    fn fresh_temp(&self, name: &str) -> Option<f32> {
+        let r = self.readings.temps.get(name)?;
+        self.readings.get_temp(name, self.max_age(r.source))
+    }
+
+    /// Sets wort and fridge temperatures from the latest readings,
+    /// unless they are too old
+    fn refresh_temps(&mut self) {
+        self.temp_wort = self.fresh_temp("wort");
+        self.temp_fridge = self.fresh_temp("fridge");
 
         if self.temp_wort.is_some() {
             self.wort_valid_time = Instant::now();
         }
 
-        if let Some(t) = self.temp_wort {
-            send!(self.timeseries.add("wort", t));
+        // only once readings have arrived
+        if self.readings.temps.is_empty() && self.readings.failed.is_empty() {
+            return
         }
+        self.note_missing("wort", self.temp_wort.is_none());
+        self.note_missing("fridge", self.temp_fridge.is_none());
+    }
 
-        if let Some(t) = self.temp_fridge {
-            send!(self.timeseries.add("fridge", t));
+    /// Records gravity, battery and angle, and the temperature as a reading
+    pub async fn add_ispindel(&mut self, report: ispindel::Report) -> ActorResult<()> {
+        debug!("add_ispindel {report:?}");
+        let id = report.sensor_id();
+        let name = self.aliases.get(&id).unwrap_or(&id).to_string();
+        if let Some(sg) = report.specific_gravity(self.config.ispindel_gravity) {
+            let series = format!("{name}.gravity");
+            send!(self.timeseries.add(series.clone(), sg));
+            self.last_gravity_series = Some(series);
+            // the reading is still recorded
+            if let Err(e) = self.check_batch().await {
+                warn!("Checking batch failed: {e:#}");
+            }
+        }
+        if let Some(b) = report.battery {
+            send!(self.timeseries.add(format!("{name}.battery"), b));
         }
+        // for recalibrating the gravity formula
+        if let Some(a) = report.angle {
+            send!(self.timeseries.add(format!("{name}.angle"), a));
+        }
+        if let Some(t) = report.celsius() {
+            let mut r = Readings::new();
+            r.add(&id, Reading::new(t, Source::ISpindel));
+            r.calibrate(&self.config.calibration);
+            self.add_readings(r).await;
+        }
+        Produces::ok(())
+    }
+
+    /// The current batch and its progress
+    pub async fn batch(&mut self) -> ActorResult<(Option<Batch>, Option<Progress>)> {
+        let Some(b) = self.batch.clone() else {
+            return Produces::ok((None, None))
+        };
+        let p = self.batch_progress(&b).await?;
+        Produces::ok((Some(b), Some(p)))
+    }
+
+    async fn batch_progress(&mut self, b: &Batch) -> Result<Progress> {
+        // stored points are quantised, the first may be a bit before the start
+        let start = b.started_time() - chrono::Duration::seconds(TIMESERIES_QUANTISE as i64);
+        let g = call!(self.timeseries.get(b.gravity_series.clone(), start)).await?;
+        Ok(b.progress(&g, self.config.batch_stable_days))
+    }
+
+    /// Adds an event when fermentation stalls or finishes
+    async fn check_batch(&mut self) -> Result<()> {
+        let Some(b) = self.batch.clone() else {
+            return Ok(())
+        };
+        let p = self.batch_progress(&b).await?;
+        if self.batch_state.is_some_and(|s| s != p.state)
+            && matches!(p.state, batch::State::Stalled | batch::State::Finished) {
+            let g = p.gravity.map(|g| format!(" at {g:.3}")).unwrap_or_default();
+            self.event("fermentation", format!("{} {}{g}", b.name, p.state));
+        }
+        self.batch_state = Some(p.state);
+        self.follow_profile(p.attenuation).await;
+        Ok(())
+    }
+
+    /// Starts a new batch, or ends the current one with `None`
+    pub async fn set_batch(&mut self, batch: Option<Batch>) -> ActorResult<Result<()>> {
+        let res = match batch {
+            Some(mut b) => {
+                if b.gravity_series.trim().is_empty() {
+                    b.gravity_series = self.last_gravity_series.clone().unwrap_or_default();
+                }
+                b.check().and_then(|_| b.save(self.config)).map(|_| Some(b))
+            }
+            None => Batch::remove(self.config).map(|_| None),
+        };
+        let b = match res {
+            Ok(b) => b,
+            Err(e) => {
+                warn!("Failed setting batch: {e:#}");
+                return Produces::ok(Err(e))
+            }
+        };
+
+        match &b {
+            Some(b) => self.event("batch", format!("Started {}, OG {:.3}", b.name, b.og)),
+            None => if let Some(old) = &self.batch {
+                self.event("batch", format!("Ended {}", old.name))
+            }
+        }
+        self.batch = b;
+        self.batch_state = None;
+        self.apply_step().await;
+        Produces::ok(Ok(()))
+    }
+
+    /// Records a timeline event
+    pub async fn add_event(&mut self, kind: String, note: String) -> ActorResult<()> {
+        info!("Event {kind}: {note}");
+        send!(self.timeseries.add_event(kind, note));
+        Produces::ok(())
+    }
+
+    pub async fn events(&mut self, start: DateTime<Utc>, end: DateTime<Utc>) -> ActorResult<Vec<Event>> {
+        Ok(call!(self.timeseries.get_events(start, end)))
+    }
+
+    fn event(&self, kind: &str, note: String) {
+        send!(self.timeseries.add_event(kind.into(), note));
+    }
+
+    /// Adds events when a sensor goes missing or comes back
+    fn note_missing(&mut self, name: &str, missing: bool) {
+        if missing && self.missing_sensors.insert(name.into()) {
+            self.event("sensor missing", format!("{name} sensor has no reading"));
+        }
+        if !missing && self.missing_sensors.remove(name) {
+            self.event("sensor restored", format!("{name} sensor is back"));
+        }
+    }
+
+    /// All sensors seen since startup, and any aliased ones that haven't been
+    pub async fn sensors(&mut self) -> ActorResult<Vec<SensorInfo>> {
+        let mut all = self.sensors.clone();
+        for id in self.aliases.names.keys() {
+            all.entry(id.clone()).or_insert_with(|| SensorInfo::new(id));
+        }
+        let v = all.into_values().map(|mut s| {
+            s.alias = self.aliases.get(&s.id).map(|a| a.to_string());
+            s
+        }).collect();
+        Produces::ok(v)
+    }
+
+    /// Names of recorded sensors other than wort and fridge, as of the latest readings
+    pub async fn extra_series(&mut self) -> ActorResult<Vec<String>> {
+        let mut v: Vec<String> = self.readings.temps.keys()
+            .filter(|n| *n != "wort" && *n != "fridge")
+            .cloned()
+            .collect();
+        v.sort();
+        Produces::ok(v)
+    }
 
-        self.update();
+    /// Sets a sensor alias, empty to remove it. Takes effect from the next reading.
+    pub async fn set_alias(&mut self, id: String, alias: String) -> ActorResult<Result<()>> {
+        let mut aliases = self.aliases.clone();
+        if let Err(e) = aliases.set(&id, &alias) {
+            return Produces::ok(Err(e));
+        }
+        if aliases == self.aliases {
+            return Produces::ok(Ok(()));
+        }
+        if let Err(e) = aliases.save(self.config) {
+            error!("Failed saving sensor aliases: {e}");
+            return Produces::ok(Err(e));
+        }
+        self.aliases = aliases;
+        let note = match self.aliases.get(&id) {
+            Some(a) => format!("{id} is now {a}"),
+            None => format!("{id} has no alias"),
+        };
+        info!("Sensor alias: {note}");
+        self.event("sensor alias", note);
+        Produces::ok(Ok(()))
     }
 
     pub async fn history(&mut self, name: String, start: DateTime<Utc>) -> ActorResult<Seq> {
@@ -201,17 +540,91 @@ impl Fridge {
         Ok(call!(self.timeseries.get_step(name, start)))
     }
 
-    pub async fn set_params(&mut self, p: Params) -> ActorResult<Result<()>> {
-        self.params = p;
+    /// When the history database was last saved to disk
+    pub async fn last_flush(&mut self) -> ActorResult<DateTime<Utc>> {
+        Ok(call!(self.timeseries.last_flush()))
+    }
+
+    /// When the current batch started, otherwise when the fridge was last set
+    /// running if that is still in the history
+    pub async fn batch_start(&mut self) -> ActorResult<Option<DateTime<Utc>>> {
+        if let Some(b) = &self.batch {
+            return Produces::ok(Some(b.started_time()))
+        }
+        Ok(call!(self.timeseries.last_step_time(RUNNING_SERIES.into(), 1.0)))
+    }
+
+    pub async fn export(
+        &mut self,
+        name: String,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> ActorResult<Vec<ExportRow>> {
+        Ok(call!(self.timeseries.export(name, start, end)))
+    }
+
+    /// Returns the new params with an incremented revision, or a `StaleParams`
+    /// error if `p` wasn't based on the current revision.
+    pub async fn set_params(&mut self, p: Params) -> ActorResult<Result<Params>> {
+        match self.params.updated(p) {
+            Ok(p) => self.params = p,
+            Err(e) => {
+                info!("Rejecting stale params, current revision is {}", self.params.revision);
+                return Produces::ok(Err(e.into()));
+            }
+        }
+        let res = self.params_changed().await;
+        Produces::ok(res.map(|_| self.params.clone()))
+    }
+
+    /// Forces the fridge on or off until a time, or back to automatic with `None`
+    pub async fn set_override(&mut self, manual: Option<ManualOverride>) -> ActorResult<Result<Params>> {
+        if let Some(m) = &manual {
+            if m.until_time() <= Utc::now() {
+                return Produces::ok(Err(anyhow!("Override needs a time in the future")))
+            }
+        }
+        let note = match (&manual, &self.params.manual) {
+            (Some(m), _) => format!("Fridge {}", m.describe()),
+            (None, Some(_)) => "Cancelled, back to automatic".into(),
+            (None, None) => return Produces::ok(Ok(self.params.clone())),
+        };
+        self.event("override", note);
+        self.params.manual = manual;
+        self.params.revision += 1;
+        let res = self.params_changed().await;
+        Produces::ok(res.map(|_| self.params.clone()))
+    }
+
+    /// Returns to automatic control once an override expires
+    async fn check_override(&mut self) {
+        let Some(m) = &self.params.manual else {
+            return
+        };
+        if m.active(Utc::now()) {
+            return
+        }
+        self.event("override", format!("Ended, was {}", m.describe()));
+        self.params.manual = None;
+        self.params.revision += 1;
+        // errors are logged
+        let _ = self.params_changed().await;
+    }
+
+    /// Acts on and saves new `self.params`
+    async fn params_changed(&mut self) -> Result<()> {
         let pp = to_string_pretty(&self.params).unwrap_or("Failed serialising params".into());
         info!("New params: {pp}");
 
         // quickly update the fridge for real world interactivity
-        self.update();
+        self.update().await;
 
         send!(self
             .timeseries
-            .add_step("setpoint", self.params.fridge_setpoint));
+            .add_step(SETPOINT_SERIES, self.params.fridge_setpoint));
+        send!(self
+            .timeseries
+            .add_step(RUNNING_SERIES, self.params.running as u8 as f32));
         send!(self.timeseries.save());
         let res = self.params.save(self.config);
 
@@ -219,15 +632,63 @@ impl Fridge {
             // log it too
             error!("Failed saving params: {e}");
         }
-        Produces::ok(res)
+        res
+    }
+
+    /// Moves the batch profile along, `attenuation` is from gravity readings if known
+    async fn follow_profile(&mut self, attenuation: Option<f32>) {
+        let Some(b) = &mut self.batch else {
+            return
+        };
+        if !b.advance(Utc::now(), attenuation) {
+            return
+        }
+        if let Err(e) = b.save(self.config) {
+            error!("Failed saving batch: {e:#}");
+        }
+        if let Some(st) = b.current_step() {
+            let note = format!("{}: {}, {:.1}°", b.name, st.name, st.setpoint);
+            self.event("profile", note);
+        }
+        self.apply_step().await;
+    }
+
+    /// Sets the fridge to the current profile step's setpoint
+    async fn apply_step(&mut self) {
+        let Some(setpoint) = self.batch.as_ref()
+            .and_then(|b| b.current_step())
+            .map(|st| st.setpoint) else {
+            return
+        };
+        if self.params.fridge_setpoint == setpoint {
+            return
+        }
+        info!("Profile setpoint {setpoint}°");
+        self.params.fridge_setpoint = setpoint;
+        self.params.revision += 1;
+        // errors are logged
+        let _ = self.params_changed().await;
     }
 
     pub async fn get_status(&mut self) -> ActorResult<Status> {
+        let now = Utc::now();
+        let reading_age: BTreeMap<String, u64> = self.readings.temps.iter()
+            .map(|(n, r)| (n.clone(), r.age(now).as_secs()))
+            .collect();
+        let stale = self.readings.temps.iter()
+            .filter(|(_, r)| r.age(now) > self.max_age(r.source))
+            .map(|(n, _)| n.clone())
+            .collect();
         let s = Status {
             params: self.params.clone(),
             on: self.on,
             temp_wort: self.temp_wort,
             temp_fridge: self.temp_fridge,
+            readings: self.readings.clone(),
+            reading_age,
+            stale,
+            rejected: self.filters.rejected(),
+            alarms: self.alarms.clone(),
             off_duration: Instant::now() - self.last_off_time,
             fridge_delay: Duration::from_secs(self.config.fridge_delay),
             overshoot_interval: self.config.overshoot_interval,
@@ -238,148 +699,190 @@ impl Fridge {
         Produces::ok(s)
     }
 
-    fn make_output(config: &Config) -> Result<FridgeOutput> {
-        if config.testmode || config.dryrun {
-            Ok(FridgeOutput::Fake)
-        } else {
-            let mut chip = gpio_cdev::Chip::new("/dev/gpiochip0").context("gpiochip0 failed")?;
-            let pin = chip
-                .get_line(config.fridge_gpio_pin)
-                .with_context(|| format!("gpio line {} failed", config.fridge_gpio_pin))?;
-            let output = pin
-                .request(gpio_cdev::LineRequestFlags::OUTPUT, 0, "fridge")
-                .context("gpio output failed")?;
-            Ok(FridgeOutput::Gpio(output))
-        }
-    }
-
-    fn turn_off(&mut self) {
+    async fn turn_off(&mut self) {
         info!("Turning fridge off");
-        if let Err(e) = self.turn(false) {
+        if let Err(e) = self.turn(false).await {
             error!("Turning off failed: {e}");
         }
         self.last_off_time = Instant::now();
     }
 
-    fn turn_on(&mut self) {
+    async fn turn_on(&mut self) {
         info!("Turning fridge on");
-        if let Err(e) = self.turn(true) {
+        if let Err(e) = self.turn(true).await {
             error!("Turning on failed: {e}")
         }
     }
 
     /// Generally use turn_on()/turn_off() instead.
-    fn turn(&mut self, on: bool) -> Result<()> {
-        match &self.output {
-            FridgeOutput::Gpio(pin) => pin.set_value(on.into()).context("Couldn't change pin")?,
-            FridgeOutput::Fake => debug!("fridge turns {}", if on { "on" } else { "off" }),
-        }
+    async fn turn(&mut self, on: bool) -> Result<()> {
+        // separate locks, so the supervisor can get in between
+        let res = match output::blocking(&self.output, move |o| o.set(on)).await {
+            Ok(()) => output::blocking(&self.output, move |o| o.verify(on)).await,
+            Err(e) => Err(e),
+        };
+        self.set_alarm("output", res.as_ref().err().map(|e| format!("{e:#}")));
+        res?;
         self.on = on;
         self.integrator.turn(on);
+        self.stuck.switched(on, Instant::now());
+        send!(self.timeseries.add_step(COMPRESSOR_SERIES, on as u8 as f32));
         Ok(())
     }
 
-    /// Must be called after every state change.
-    /// Turns the fridge off and on
-    fn update(&mut self) {
-        // fridge_min/fridge_max are only used when wort is unavailable
-        let fridge_min = self.params.fridge_setpoint - self.params.fridge_range_lower;
-        let fridge_max = self.params.fridge_setpoint + self.params.fridge_range_upper;
-        let wort_max = self.params.fridge_setpoint + self.params.fridge_difference;
-        let off_duration = Instant::now() - self.last_off_time;
-
-        debug!("off_duration {:?}", off_duration);
-
-        if !self.params.running {
-            if self.on {
-                info!("Disabled, turning fridge off");
-                self.turn_off();
-            }
-            return;
+    /// Notices if the relay was switched some other way, such as by hand on a plug
+    async fn check_output(&mut self) {
+        let interval = Duration::from_secs(self.config.output.readback_interval);
+        if interval.is_zero() || self.last_readback.elapsed() < interval {
+            return
         }
-
-        // handle broken wort sensor
-        if self.temp_wort.is_none() {
-            let invalid_time = Instant::now() - self.wort_valid_time;
-            let skip = invalid_time < Duration::new(self.config.fridge_wort_invalid_time, 0);
-            self.often_badwort.and_then(|| {
-                if skip {
-                    warn!("Has only been invalid for {:?}, waiting", invalid_time);
-                } else {
-                    warn!("Invalid wort sensor for {:?} secs", invalid_time);
-                }
-            });
-            if skip {
-                return;
+        self.last_readback = Instant::now();
+        let (got, desc) = output::blocking(&self.output, |o| (o.get(), o.describe())).await;
+        let actual = match got {
+            Ok(Some(a)) => a,
+            Ok(None) => return,
+            Err(e) => {
+                let msg = format!("Reading back {desc} failed: {e:#}");
+                warn!("{msg}");
+                self.set_alarm("readback", Some(msg));
+                return
             }
+        };
+        self.set_alarm("readback", None);
+        if actual == self.on {
+            return
         }
+        let note = format!("Relay was found {}, expected {}", onoff(actual), onoff(self.on));
+        warn!("{note}");
+        self.event("output", note);
+        // take it as it is, update() then decides what it should be
+        self.on = actual;
+        self.integrator.turn(actual);
+        self.stuck.switched(actual, Instant::now());
+        if !actual {
+            self.last_off_time = Instant::now();
+        }
+        send!(self.timeseries.add_step(COMPRESSOR_SERIES, actual as u8 as f32));
+    }
 
-        if self.temp_fridge.is_none() {
-            self.often_badfridge
-                .and_then(|| warn!("Invalid fridge sensor"));
+    /// Such as "wort 18.2° fridge 4.1° cooling", for systemd
+    fn status_line(&self) -> String {
+        let deg = |t: Option<f32>| t.map(|t| format!("{t:.1}°")).unwrap_or("?".into());
+        let state = match (&self.params.manual, self.params.running, self.on) {
+            (Some(m), _, _) => m.describe(),
+            (None, false, _) => "stopped".into(),
+            (None, true, true) => "cooling".into(),
+            (None, true, false) => "idle".into(),
+        };
+        let mut s = format!("wort {} fridge {} {state}", deg(self.temp_wort), deg(self.temp_fridge));
+        if !self.alarms.is_empty() {
+            s += &format!(", {} alarm", self.alarms.len());
         }
+        s
+    }
 
-        // The main decision
-        if self.on {
-            let on_time = self.integrator.integrate().as_secs() as f32;
-            let on_ratio = on_time / self.config.overshoot_interval as f32;
-            let overshoot = self.params.overshoot_factor * on_ratio;
-            debug!("on_percent {}, overshoot {}", on_ratio * 100.0, overshoot);
+    /// Looks for a relay that's stuck, from the fridge temperature
+    fn check_stuck(&mut self) {
+        let Some(t) = self.temp_fridge else {
+            return
+        };
+        // a working fridge should be cooling above its range
+        let warm = self.params.fridge_setpoint + self.params.fridge_range_upper;
+        // no temperature in the text, a changed alarm is logged again
+        let msg = match self.stuck.add(t, warm, Instant::now()) {
+            Some(Stuck::On) => Some("Relay may be stuck on, fridge still cooling while off".into()),
+            Some(Stuck::Off) => Some("Relay may be stuck off, fridge not cooling while on".into()),
+            None => None,
+        };
+        self.set_alarm("stuck", msg);
+    }
 
-            match (self.temp_wort, self.temp_fridge) {
-                (Some(t), _) if self.params.use_wort => {
-                    if t - overshoot < self.params.fridge_setpoint {
-                        info!("Wort has cooled enough, {t}° (overshoot {overshoot}°)");
-                        self.turn_off();
+    /// Raises an alarm, or clears it with `None`. Changes are logged and added as events.
+    fn set_alarm(&mut self, name: &str, msg: Option<String>) {
+        match msg {
+            Some(msg) => {
+                if self.alarms.get(name) != Some(&msg) {
+                    error!("Alarm: {msg}");
+                    if !self.alarms.contains_key(name) {
+                        self.event("alarm", msg.clone());
                     }
+                    self.alarms.insert(name.into(), msg);
                 }
-                (_, Some(t)) => {
-                    if t < fridge_min {
-                        warn!("Fridge off fallback, fridge {t}°, min {fridge_min}°");
-                        if self.temp_wort.is_none() {
-                            warn!(
-                                "Wort has been invalid for {:?}",
-                                Instant::now() - self.wort_valid_time
-                            );
-                        }
-                        self.turn_off();
-                    }
+            }
+            None => {
+                if let Some(old) = self.alarms.remove(name) {
+                    info!("Alarm cleared: {old}");
+                    self.event("alarm cleared", old);
                 }
-                _ => (),
             }
-        } else {
-            // Also is the flag to turn it on.
-            let mut turn_on_reason = None;
-
-            match (self.temp_wort, self.temp_fridge) {
-                (Some(t), _) if self.params.use_wort => {
-                    if t >= wort_max {
-                        turn_on_reason = Some((
-                            format!("Wort is too hot {t}°, max {wort_max}°"),
-                            log::Level::Info,
-                        ));
+        }
+    }
+
+    /// Must be called after every state change.
+    /// Turns the fridge off and on
+    async fn update(&mut self) {
+        self.refresh_temps();
+        let now = Utc::now();
+
+        let auto = self.params.running
+            && !self.params.manual.as_ref().is_some_and(|m| m.active(now));
+        if auto {
+            // handle broken wort sensor
+            if self.temp_wort.is_none() {
+                let invalid_time = Instant::now() - self.wort_valid_time;
+                let skip = invalid_time < Duration::new(self.config.fridge_wort_invalid_time, 0);
+                self.often_badwort.and_then(|| {
+                    if skip {
+                        warn!("Has only been invalid for {:?}, waiting", invalid_time);
+                    } else {
+                        warn!("Invalid wort sensor for {:?} secs", invalid_time);
                     }
+                });
+                if skip {
+                    return;
                 }
-                (_, Some(t)) => {
-                    if t >= fridge_max {
-                        turn_on_reason = Some((
-                            format!("Fridge too hot fallback, fridge {t}°, max {fridge_max}°"),
-                            log::Level::Warn,
-                        ));
-                    }
+                // only once readings have arrived, not at startup
+                if !self.wort_fallback && self.missing_sensors.contains("wort") {
+                    self.wort_fallback = true;
+                    self.event("wort fallback", format!("No wort reading for {}, using fridge range",
+                        invalid_time.as_short_str()));
                 }
-                _ => (),
+            } else if self.wort_fallback {
+                self.wort_fallback = false;
+                self.event("wort restored", "Using wort temperature again".into());
             }
 
-            if let Some((reason, loglevel)) = turn_on_reason {
-                // The fridge should turn on
+            if self.temp_fridge.is_none() {
+                self.often_badfridge
+                    .and_then(|| warn!("Invalid fridge sensor"));
+            }
+        }
+
+        let overshoot = if self.on {
+            let on_time = self.integrator.integrate().as_secs() as f32;
+            let on_ratio = on_time / self.config.overshoot_interval as f32;
+            let overshoot = self.params.overshoot_factor * on_ratio;
+            debug!("on_percent {}, overshoot {}", on_ratio * 100.0, overshoot);
+            overshoot
+        } else {
+            0.0
+        };
 
+        match decide(&self.params, now, self.on, self.temp_wort, self.temp_fridge, overshoot) {
+            Switch::Stay => (),
+            Switch::Off(level, reason) => {
+                log!(level, "{reason}");
+                self.turn_off().await;
+            }
+            Switch::On(level, reason) => {
+                let off_duration = Instant::now() - self.last_off_time;
+                debug!("off_duration {:?}", off_duration);
                 // To avoid bad things happening to the fridge motor (?)
                 // When it turns off don't start up again for at least FRIDGE_DELAY
                 if off_duration < Duration::from_secs(self.config.fridge_delay) {
                     self.often_tooearly.and_then(|| {
                         log!(
-                            loglevel,
+                            level,
                             "{}, but fridge skipping, too early ({} seconds left)",
                             reason,
                             self.config.fridge_delay - off_duration.as_secs()
@@ -387,10 +890,185 @@ impl Fridge {
                     });
                 } else {
                     // Really turn on.
-                    log!(loglevel, "{reason}");
-                    self.turn_on();
+                    log!(level, "{reason}");
+                    self.turn_on().await;
                 }
             }
         }
     }
 }
+
+/// What `update()` does with the relay
+#[derive(Debug, Clone, PartialEq)]
+enum Switch {
+    Stay,
+    Off(log::Level, String),
+    /// Still subject to `fridge_delay`
+    On(log::Level, String),
+}
+
+/// The on/off decision from the current temperatures, `None` if missing or stale.
+/// `overshoot` is taken off the wort while on, so that it turns off early.
+fn decide(p: &Params, now: DateTime<Utc>, on: bool, wort: Option<f32>, fridge: Option<f32>,
+    overshoot: f32) -> Switch {
+    use log::Level::{Info, Warn};
+
+    // fridge_min/fridge_max are only used when wort is unavailable, and
+    // fridge_min as a floor while forced on
+    let fridge_min = p.fridge_setpoint - p.fridge_range_lower;
+    let fridge_max = p.fridge_setpoint + p.fridge_range_upper;
+    let wort_max = p.fridge_setpoint + p.fridge_difference;
+
+    // before running, a forced override applies even when stopped
+    if let Some(m) = p.manual.as_ref().filter(|m| m.active(now)) {
+        // don't freeze the brew if it's left forced on
+        let too_cold = fridge.is_some_and(|t| t < fridge_min);
+        return match (m.mode, on) {
+            (ManualMode::Off, true) => Switch::Off(Info, "Override, turning fridge off".into()),
+            (ManualMode::On, true) if too_cold => Switch::Off(Info,
+                format!("Override on, but fridge is below {fridge_min:.1}°, turning off")),
+            (ManualMode::On, false) if !too_cold => Switch::On(Info, "Override on".into()),
+            _ => Switch::Stay,
+        }
+    }
+
+    if !p.running {
+        return if on {
+            Switch::Off(Info, "Disabled, turning fridge off".into())
+        } else {
+            Switch::Stay
+        }
+    }
+
+    let wort = wort.filter(|_| p.use_wort);
+    if on {
+        match (wort, fridge) {
+            (Some(t), _) if t - overshoot < p.fridge_setpoint => Switch::Off(Info,
+                format!("Wort has cooled enough, {t}° (overshoot {overshoot}°)")),
+            (None, Some(t)) if t < fridge_min => Switch::Off(Warn,
+                format!("Fridge off fallback, fridge {t}°, min {fridge_min}°")),
+            // nothing fresh to go by, don't run blind
+            (None, None) => Switch::Off(Warn, "No wort or fridge temperature, turning off".into()),
+            _ => Switch::Stay,
+        }
+    } else {
+        match (wort, fridge) {
+            (Some(t), _) if t >= wort_max => Switch::On(Info,
+                format!("Wort is too hot {t}°, max {wort_max}°")),
+            (None, Some(t)) if t >= fridge_max => Switch::On(Warn,
+                format!("Fridge too hot fallback, fridge {t}°, max {fridge_max}°")),
+            _ => Switch::Stay,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level::{Info, Warn};
+
+    fn params() -> Params {
+        Params {
+            fridge_setpoint: 18.0,
+            fridge_difference: 0.2,
+            running: true,
+            use_wort: true,
+            fridge_range_lower: 3.0,
+            fridge_range_upper: 3.0,
+            overshoot_factor: 1.0,
+            manual: None,
+            revision: 0,
+        }
+    }
+
+    fn is_on(s: &Switch) -> bool {
+        matches!(s, Switch::On(..))
+    }
+
+    fn is_off(s: &Switch) -> bool {
+        matches!(s, Switch::Off(..))
+    }
+
+    #[test]
+    fn override_decision() {
+        let now = Utc::now();
+        let forced = |mode| Params {
+            manual: Some(ManualOverride { mode, until: now.timestamp() + 3600 }),
+            ..params()
+        };
+
+        // forced off, even with a hot wort
+        let p = forced(ManualMode::Off);
+        assert!(is_off(&decide(&p, now, true, Some(25.0), Some(20.0), 0.0)));
+        assert_eq!(decide(&p, now, false, Some(25.0), Some(20.0), 0.0), Switch::Stay);
+
+        // forced on, even with a cold wort and when stopped
+        let p = Params { running: false, ..forced(ManualMode::On) };
+        assert!(is_on(&decide(&p, now, false, Some(10.0), Some(16.0), 0.0)));
+        assert_eq!(decide(&p, now, true, Some(10.0), Some(16.0), 0.0), Switch::Stay);
+        assert!(is_on(&decide(&p, now, false, None, None, 0.0)));
+
+        // but not below fridge_min
+        assert!(is_off(&decide(&p, now, true, Some(10.0), Some(14.9), 0.0)));
+        assert_eq!(decide(&p, now, false, Some(10.0), Some(14.9), 0.0), Switch::Stay);
+        assert!(is_on(&decide(&p, now, false, Some(10.0), Some(15.1), 0.0)));
+
+        // expired, back to automatic
+        let mut p = forced(ManualMode::On);
+        p.manual.as_mut().unwrap().until = now.timestamp() - 1;
+        assert!(is_off(&decide(&p, now, true, Some(17.0), Some(10.0), 0.0)));
+        assert!(!p.manual.as_ref().unwrap().active(now));
+    }
+
+    #[test]
+    fn decision() {
+        let now = Utc::now();
+        let p = params();
+        assert_eq!(decide(&p, now, false, Some(18.3), Some(10.0), 0.0),
+            Switch::On(Info, "Wort is too hot 18.3°, max 18.2°".into()));
+        assert_eq!(decide(&p, now, false, Some(18.1), Some(25.0), 0.0), Switch::Stay);
+        // overshoot turns off early
+        assert_eq!(decide(&p, now, true, Some(18.4), Some(10.0), 0.0), Switch::Stay);
+        assert!(is_off(&decide(&p, now, true, Some(18.4), Some(10.0), 0.5)));
+
+        // the fridge range without wort
+        assert_eq!(decide(&p, now, false, None, Some(21.0), 0.0),
+            Switch::On(Warn, "Fridge too hot fallback, fridge 21°, max 21°".into()));
+        assert!(is_off(&decide(&p, now, true, None, Some(14.9), 0.0)));
+        let p = Params { use_wort: false, ..params() };
+        assert!(is_on(&decide(&p, now, false, Some(25.0), Some(21.0), 0.0)));
+        assert_eq!(decide(&p, now, false, Some(25.0), Some(20.0), 0.0), Switch::Stay);
+
+        let p = Params { running: false, ..params() };
+        assert!(is_off(&decide(&p, now, true, Some(25.0), Some(25.0), 0.0)));
+        assert_eq!(decide(&p, now, false, Some(25.0), Some(25.0), 0.0), Switch::Stay);
+    }
+
+    #[test]
+    fn stale_readings() {
+        let now = Utc::now();
+        let max_age = Duration::from_secs(60);
+        let reading = |temp, age| {
+            let mut r = Reading::new(temp, Source::OneWire);
+            r.time = now - chrono::Duration::seconds(age);
+            r
+        };
+        let p = params();
+
+        // a stale wort falls back to the fridge range
+        let mut r = Readings::new();
+        r.add("wort", reading(25.0, 300));
+        r.add("fridge", reading(21.5, 10));
+        let (wort, fridge) = (r.get_temp("wort", max_age), r.get_temp("fridge", max_age));
+        assert_eq!((wort, fridge), (None, Some(21.5)));
+        assert_eq!(decide(&p, now, false, wort, fridge, 0.0),
+            Switch::On(Warn, "Fridge too hot fallback, fridge 21.5°, max 21°".into()));
+
+        // both stale turns it off
+        r.add("fridge", reading(21.5, 300));
+        let (wort, fridge) = (r.get_temp("wort", max_age), r.get_temp("fridge", max_age));
+        assert_eq!((wort, fridge), (None, None));
+        assert!(is_off(&decide(&p, now, true, wort, fridge, 0.0)));
+        assert_eq!(decide(&p, now, false, wort, fridge, 0.0), Switch::Stay);
+    }
+}
@@ -0,0 +1,428 @@
+//! Temperature history plots
+
+#[allow(unused_imports)]
+use {
+    log::{debug, error, info, warn},
+    anyhow::{anyhow,Result,Context,bail},
+};
+
+use std::str::FromStr;
+
+use act_zero::*;
+
+use chrono::{DateTime,offset::Utc,Local};
+
+use plotters::prelude::*;
+use plotters::coord::Shift;
+use plotters::coord::ranged1d::{KeyPointHint, NoDefaultFormatting, ValueFormatter};
+
+use crate::fridge::{COMPRESSOR_SERIES, Fridge, SETPOINT_SERIES};
+use crate::timeseries::{Event, Seq};
+
+/// Time span shown by a graph, ending now.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Range {
+    Hour,
+    Hours8,
+    Day,
+    Week,
+    /// Since the controller was last set running
+    Batch,
+}
+
+impl Range {
+    /// In the order shown on the page
+    pub const ALL: [Range; 5] = [Range::Hour, Range::Hours8, Range::Day, Range::Week, Range::Batch];
+
+    fn duration(&self) -> Option<chrono::Duration> {
+        match self {
+            Range::Hour => Some(chrono::Duration::hours(1)),
+            Range::Hours8 => Some(chrono::Duration::hours(8)),
+            Range::Day => Some(chrono::Duration::hours(24)),
+            Range::Week => Some(chrono::Duration::days(7)),
+            Range::Batch => None,
+        }
+    }
+
+    fn desc(&self) -> &'static str {
+        match self {
+            Range::Hour => "1 hour",
+            Range::Hours8 => "8 hours",
+            Range::Day => "24 hours",
+            Range::Week => "7 days",
+            Range::Batch => "batch",
+        }
+    }
+}
+
+impl FromStr for Range {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Range::ALL.iter().find(|r| r.to_string() == s).copied()
+        .ok_or_else(|| anyhow!("Unknown range '{s}'"))
+    }
+}
+
+impl std::fmt::Display for Range {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Range::Hour => "1h",
+            Range::Hours8 => "8h",
+            Range::Day => "24h",
+            Range::Week => "7d",
+            Range::Batch => "batch",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Which graph to draw
+#[derive(Debug,Clone)]
+pub struct Graph {
+    pub range: Range,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Graph {
+    pub const MIN_WIDTH: u32 = 100;
+    pub const MAX_WIDTH: u32 = 2000;
+
+    /// `height` defaults to a golden ratio of `width`
+    pub fn new(range: Range, width: u32, height: Option<u32>) -> Self {
+        let width = width.clamp(Self::MIN_WIDTH, Self::MAX_WIDTH);
+        // golden ratio is as good as any I guess
+        let ratio = (1f32 + 5f32.powf(0.5)) / 2f32;
+        let height = height.unwrap_or((width as f32 / ratio) as u32)
+            .clamp(Self::MIN_WIDTH / 2, Self::MAX_WIDTH);
+        Graph {
+            range,
+            width,
+            height,
+        }
+    }
+}
+
+struct DegreeValue {
+    lower: f32,
+    upper: f32,
+}
+
+impl DegreeValue {
+    /// Fits the axis around all values, with some margin
+    fn around<'a>(series: impl IntoIterator<Item = &'a Seq>) -> Self {
+        let (lo, hi) = series.into_iter().flatten()
+        .fold((f32::MAX, f32::MIN), |(lo, hi), (_, v)| (lo.min(*v), hi.max(*v)));
+        if lo > hi {
+            // no data
+            return DegreeValue { lower: 0.0, upper: 25.0 }
+        }
+
+        let (mut lo, mut hi) = (lo - 1.0, hi + 1.0);
+        // avoid magnifying noise when the temperature is steady
+        let min_span = 4.0;
+        if hi - lo < min_span {
+            let mid = (hi + lo) / 2.0;
+            lo = mid - min_span / 2.0;
+            hi = mid + min_span / 2.0;
+        }
+        DegreeValue { lower: lo.floor(), upper: hi.ceil() }
+    }
+
+    fn step(&self) -> f32 {
+        let span = self.upper - self.lower;
+        [1.0, 2.0, 5.0, 10.0, 20.0].into_iter()
+        .find(|s| span / s <= 6.0)
+        .unwrap_or(50.0)
+    }
+}
+
+impl Ranged for DegreeValue {
+    type ValueType = f32;
+    type FormatOption = NoDefaultFormatting;
+    fn map(&self, value: &Self::ValueType, limit: (i32, i32)) -> i32 {
+        let pix: f32 = (limit.1 - limit.0) as f32;
+        limit.0 + (pix * (value - self.lower) / (self.upper - self.lower)) as i32
+    }
+
+    fn range(&self) -> std::ops::Range<Self::ValueType> {
+        self.lower..self.upper
+    }
+
+    fn key_points<Hint: KeyPointHint>(&self, _hint: Hint) -> Vec<Self::ValueType> {
+        let step = self.step();
+        let s = (self.lower / step).ceil() as i32;
+        let e = (self.upper / step).floor() as i32;
+        (s..=e).map(|i| i as f32 * step).collect()
+    }
+}
+
+impl ValueFormatter<f32> for DegreeValue {
+    fn format(v: &f32) -> String {
+        format!("{:.0}°", v)
+    }
+}
+
+/// Returns the periods where a stepwise series is on (non-zero), clipped
+/// to start no earlier than `start`.
+fn on_periods(seq: &Seq, start: DateTime<Utc>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    seq.windows(2).filter_map(|w| {
+        let (a, b) = (w[0], w[1]);
+        if a.1 > 0.5 && b.1 > 0.5 && b.0 > start {
+            Some((a.0.max(start), b.0))
+        } else {
+            None
+        }
+    }).collect()
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Returns an invisible hover target with a tooltip for each point, at most
+/// one every few pixels.
+fn tooltips<F>(label: &str, seq: &Seq, coord: F, width: u32) -> Vec<String>
+    where F: Fn(&(DateTime<Utc>, f32)) -> (i32, i32) {
+    let every = (seq.len() / (width as usize / 4).max(1)).max(1);
+    seq.iter().step_by(every).map(|p| {
+        let (x, y) = coord(p);
+        let when = p.0.with_timezone(&Local).format("%a %H:%M");
+        format!("<circle class=\"tip\" cx=\"{x}\" cy=\"{y}\" r=\"4\">\
+            <title>{} {:.1}° {when}</title></circle>", xml_escape(label), p.1)
+    }).collect()
+}
+
+/// History fetched for drawing
+struct GraphData {
+    time1: DateTime<Utc>,
+    time2: DateTime<Utc>,
+    worts: Seq,
+    fridges: Seq,
+    /// Other sensors such as ambient, by name
+    others: Vec<(String, Seq)>,
+    setpoints: Seq,
+    compressor: Seq,
+    events: Vec<Event>,
+}
+
+impl GraphData {
+    async fn fetch(fridge: &WeakAddr<Fridge>, graph: &Graph) -> Result<Self> {
+        let time2 = Utc::now();
+        let time1 = match graph.range.duration() {
+            Some(d) => time2 - d,
+            None => call!(fridge.batch_start()).await?
+                .unwrap_or(time2 - chrono::Duration::hours(8)),
+        };
+
+        let mut others = vec![];
+        for name in call!(fridge.extra_series()).await? {
+            let seq = call!(fridge.history(name.clone(), time1)).await?;
+            others.push((name, seq));
+        }
+
+        Ok(GraphData {
+            time1,
+            time2,
+            worts: call!(fridge.history("wort".into(), time1)).await?,
+            fridges: call!(fridge.history("fridge".into(), time1)).await?,
+            others,
+            setpoints: call!(fridge.history_step(SETPOINT_SERIES.into(), time1)).await?,
+            compressor: call!(fridge.history_step(COMPRESSOR_SERIES.into(), time1)).await?,
+            events: call!(fridge.events(time1, time2)).await?,
+        })
+    }
+}
+
+/// Only has the characters needed for labels, see fonts/LICENSE-DejaVu
+static FONT: &[u8] = include_bytes!("../fonts/DejaVuSans-subset.ttf");
+
+/// Plotters needs a font for any text, including in svg output
+fn register_font() -> Result<()> {
+    static REGISTERED: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    let ok = *REGISTERED.get_or_init(|| {
+        plotters::style::register_font("sans-serif", FontStyle::Normal, FONT).is_ok()
+    });
+    if !ok {
+        bail!("Bad graph font")
+    }
+    Ok(())
+}
+
+/// Draws the graph on any backend. Returns svg elements with tooltips
+/// for the points, since plotters can't add those itself.
+fn draw<DB>(area: &DrawingArea<DB, Shift>, d: &GraphData, graph: &Graph) -> Result<Vec<String>>
+    where DB: DrawingBackend, DB::ErrorType: 'static {
+    let time_range = d.time1..d.time2;
+    let temp_range = DegreeValue::around(
+        [&d.worts, &d.fridges, &d.setpoints].into_iter().chain(d.others.iter().map(|(_, s)| s)));
+    let (temp_lower, temp_upper) = (temp_range.lower, temp_range.upper);
+
+    let amber = RGBColor(0xff, 0xa8, 0);
+    let fridgeblue = RGBColor(0x93, 0xc8, 0xff);
+    let green = RGBColor(0x9a, 0xd7, 0x51);
+    let palecold = RGBColor(0xe4, 0xf1, 0xff);
+    let eventgrey = RGBColor(0x80, 0x80, 0x80);
+    let ruler = RGBColor(0xaa,0xaa,0xaa).stroke_width(1);
+
+    let mut plot = ChartBuilder::on(area)
+    // room for the top axis label
+    .margin_top(6)
+    .y_label_area_size(40)
+    .x_label_area_size(10)
+    .build_cartesian_2d(time_range, temp_range)?;
+
+    plot.configure_mesh()
+    .disable_x_mesh()
+    .disable_x_axis()
+    .axis_style(ruler.stroke_width(0))
+    .bold_line_style(ruler)
+    .set_all_tick_mark_size(1)
+    .x_desc(graph.range.desc())
+    .draw()?;
+
+    // behind the lines
+    plot.draw_series(on_periods(&d.compressor, d.time1).into_iter().map(|(s, e)| {
+        Rectangle::new([(s, temp_upper), (e, temp_lower)], palecold.filled())
+    }))?
+    .label("Compressor")
+    .legend(move |(x, y)| Rectangle::new([(x, y - 4), (x + 12, y + 4)], palecold.filled()));
+
+    // for other sensors, thinner so wort and fridge stand out
+    let others = [
+        RGBColor(0xc0, 0x7a, 0xd8),
+        RGBColor(0x8c, 0x6d, 0x46),
+        RGBColor(0xe8, 0x6f, 0x8a),
+        RGBColor(0x5f, 0xb8, 0xb0),
+    ];
+
+    let mut lines = vec![
+        ("Fridge", &d.fridges, fridgeblue.stroke_width(3)),
+        ("Wort", &d.worts, amber.stroke_width(3)),
+        ("Setpoint", &d.setpoints, green.stroke_width(1)),
+    ];
+    for (i, (name, seq)) in d.others.iter().enumerate() {
+        lines.push((name.as_str(), seq, others[i % others.len()].stroke_width(2)));
+    }
+
+    let mut tips = vec![];
+    for (label, seq, style) in lines {
+        plot.draw_series(LineSeries::new(seq.iter().cloned(), style))?
+        .label(label)
+        .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 12, y)], style));
+        tips.extend(tooltips(label, seq, |p| plot.backend_coord(p), graph.width));
+    }
+
+    // event markers, a line with a triangle at the top
+    for e in &d.events {
+        plot.draw_series(LineSeries::new([(e.time, temp_lower), (e.time, temp_upper)],
+            eventgrey.mix(0.6).stroke_width(1)))?;
+        plot.draw_series([TriangleMarker::new((e.time, temp_upper), 5, eventgrey.filled())])?;
+        let (x, y) = plot.backend_coord(&(e.time, temp_upper));
+        let when = e.time.with_timezone(&Local).format("%a %H:%M");
+        tips.push(format!("<circle class=\"tip\" cx=\"{x}\" cy=\"{}\" r=\"6\">\
+            <title>{}: {} {when}</title></circle>", y + 4, xml_escape(&e.kind), xml_escape(&e.note)));
+    }
+
+    plot.configure_series_labels()
+    .position(SeriesLabelPosition::UpperLeft)
+    .background_style(WHITE.mix(0.7))
+    .border_style(TRANSPARENT)
+    .label_font(("sans-serif", 11))
+    .draw()?;
+
+    Ok(tips)
+}
+
+pub async fn svg(fridge: &WeakAddr<Fridge>, graph: &Graph) -> Result<String> {
+    register_font()?;
+    let d = GraphData::fetch(fridge, graph).await?;
+
+    let mut out = String::new();
+    let area = plotters_svg::SVGBackend::with_string(&mut out, (graph.width, graph.height))
+        .into_drawing_area();
+    let tips = draw(&area, &d, graph)?;
+    area.present()?;
+    // take back 'out'
+    drop(area);
+
+    let end = out.rfind("</svg>").context("Bad svg")?;
+    out.insert_str(end, &tips.concat());
+    Ok(out)
+}
+
+/// Renders the graph as a PNG image, for places that can't show svg.
+pub async fn png(fridge: &WeakAddr<Fridge>, graph: &Graph) -> Result<Vec<u8>> {
+    let d = GraphData::fetch(fridge, graph).await?;
+    render_png(&d, graph)
+}
+
+fn render_png(d: &GraphData, graph: &Graph) -> Result<Vec<u8>> {
+    register_font()?;
+    let (w, h) = (graph.width, graph.height);
+    let mut rgb = vec![0u8; w as usize * h as usize * 3];
+    {
+        let area = BitMapBackend::with_buffer(&mut rgb, (w, h)).into_drawing_area();
+        area.fill(&WHITE)?;
+        draw(&area, d, graph)?;
+        area.present()?;
+    }
+
+    let mut out = vec![];
+    {
+        let mut enc = png::Encoder::new(&mut out, w, h);
+        enc.set_color(png::ColorType::Rgb);
+        enc.set_depth(png::BitDepth::Eight);
+        enc.write_header()?.write_image_data(&rgb)?;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn degree_axis() {
+        let t = Utc::now();
+        let d = DegreeValue::around([&vec![(t, 18.2), (t, 18.4)]]);
+        assert_eq!((d.lower, d.upper), (16.0, 21.0));
+        assert_eq!(d.key_points(10usize), vec![16.0, 17.0, 18.0, 19.0, 20.0, 21.0]);
+
+        let d = DegreeValue::around([&vec![(t, 2.1)], &vec![(t, 19.5)]]);
+        assert_eq!((d.lower, d.upper), (1.0, 21.0));
+        assert_eq!(d.key_points(10usize), vec![5.0, 10.0, 15.0, 20.0]);
+
+        for r in Range::ALL {
+            assert_eq!(r.to_string().parse::<Range>().unwrap(), r);
+        }
+    }
+
+    #[test]
+    fn render() {
+        let t = |m: i64| DateTime::from_timestamp(1_700_000_000 + m * 60, 0).unwrap();
+        let d = GraphData {
+            time1: t(0),
+            time2: t(60),
+            worts: vec![(t(1), 18.2), (t(30), 18.5)],
+            fridges: vec![(t(1), 12.0), (t(30), 10.5)],
+            others: vec![("ambient".into(), vec![(t(1), 24.0), (t(30), 25.5)])],
+            setpoints: vec![(t(0), 18.0), (t(60), 18.0)],
+            compressor: vec![(t(0), 1.0), (t(40), 1.0)],
+            events: vec![Event { time: t(20), kind: "dry hop".into(), note: "<50g>".into() }],
+        };
+        let g = Graph::new(Range::Hour, 300, None);
+        let png = render_png(&d, &g).unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+    }
+
+    #[test]
+    fn compressor_bands() {
+        let t = |m: i64| DateTime::from_timestamp(1_700_000_000 + m * 60, 0).unwrap();
+        // as returned by TimeSeries::get_step(), starting before the window
+        let seq = vec![(t(0), 1.0), (t(10), 1.0), (t(10), 0.0), (t(20), 0.0),
+            (t(20), 1.0), (t(30), 1.0)];
+        assert_eq!(on_periods(&seq, t(5)), vec![(t(5), t(10)), (t(20), t(30))]);
+        assert_eq!(on_periods(&seq, t(15)), vec![(t(20), t(30))]);
+    }
+}
@@ -0,0 +1,178 @@
+//! Subsystem checks for the `/healthz` and `/readyz` monitoring endpoints
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use anyhow::{Context, Result, anyhow};
+
+use chrono::{DateTime, Duration, offset::Utc};
+use serde::Serialize;
+use x509_parser::pem::Pem;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use super::fridge::Status;
+use super::timeseries::DEFAULT_SAVE_INTERVAL;
+
+/// A certificate is renewed halfway through its life, so this means renewal
+/// has been failing for a while
+const CERT_MIN_DAYS: i64 = 14;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Check {
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    pub fn ok(detail: impl Into<String>) -> Self {
+        Check { ok: true, detail: detail.into() }
+    }
+
+    pub fn fail(detail: impl Into<String>) -> Self {
+        Check { ok: false, detail: detail.into() }
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Health {
+    pub ok: bool,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl Health {
+    pub fn add(&mut self, name: &'static str, check: Check) {
+        self.checks.insert(name, check);
+        self.ok = self.checks.values().all(|c| c.ok);
+    }
+
+    pub fn is_ok(&self, name: &str) -> bool {
+        self.checks.get(name).is_some_and(|c| c.ok)
+    }
+}
+
+/// Recent readings, none stale or failing
+pub fn sensors(status: &Status) -> Check {
+    if status.readings.temps.is_empty() {
+        return Check::fail("No readings yet");
+    }
+    let mut problems = vec![];
+    if !status.stale.is_empty() {
+        problems.push(format!("stale {}", status.stale.join(", ")));
+    }
+    if !status.readings.failed.is_empty() {
+        problems.push(format!("failed {}", status.readings.failed.join(", ")));
+    }
+    if problems.is_empty() {
+        Check::ok(format!("{} sensors", status.readings.temps.len()))
+    } else {
+        Check::fail(problems.join(", "))
+    }
+}
+
+/// The output backend switched, reads back, and isn't stuck
+pub fn output(status: &Status) -> Check {
+    if status.alarms.is_empty() {
+        Check::ok(if status.on { "on" } else { "off" })
+    } else {
+        let a: Vec<_> = status.alarms.iter().map(|(k, v)| format!("{k}: {v}")).collect();
+        Check::fail(a.join(", "))
+    }
+}
+
+/// The history database was saved to disk recently
+pub fn flush(last: DateTime<Utc>, now: DateTime<Utc>) -> Check {
+    let age = now - last;
+    let detail = format!("Saved {}s ago", age.num_seconds());
+    // allow a couple of failures
+    if age.to_std().unwrap_or_default() < DEFAULT_SAVE_INTERVAL * 3 {
+        Check::ok(detail)
+    } else {
+        Check::fail(detail)
+    }
+}
+
+/// The ACME certificate cached in `dir` isn't close to expiring
+pub fn cert(dir: &Path, now: DateTime<Utc>) -> Check {
+    let expiry = newest_cert(dir)
+        .and_then(|p| {
+            let pem = std::fs::read(&p).with_context(|| format!("Reading {p:?}"))?;
+            cert_expiry(&pem)
+        });
+    match expiry {
+        Ok(expiry) => {
+            let days = (expiry - now).num_days();
+            let detail = format!("Expires {} ({days} days)", expiry.format("%Y-%m-%d"));
+            if expiry - now > Duration::days(CERT_MIN_DAYS) {
+                Check::ok(detail)
+            } else {
+                Check::fail(detail)
+            }
+        }
+        Err(e) => Check::fail(format!("{e:#}")),
+    }
+}
+
+/// The most recent `cached_cert_*` written by rustls-acme's `DirCache`
+fn newest_cert(dir: &Path) -> Result<std::path::PathBuf> {
+    let mut newest = None;
+    for e in std::fs::read_dir(dir).with_context(|| format!("Reading {dir:?}"))? {
+        let e = e?;
+        if !e.file_name().to_string_lossy().starts_with("cached_cert_") {
+            continue;
+        }
+        let modified = e.metadata()?.modified()?;
+        if newest.as_ref().is_none_or(|(m, _)| modified > *m) {
+            newest = Some((modified, e.path()));
+        }
+    }
+    newest.map(|(_, p)| p).ok_or_else(|| anyhow!("No certificate yet"))
+}
+
+/// The cache file has the private key followed by the certificate chain
+fn cert_expiry(pem: &[u8]) -> Result<DateTime<Utc>> {
+    for p in Pem::iter_from_buffer(pem) {
+        let p = p.context("Bad PEM")?;
+        if p.label != "CERTIFICATE" {
+            continue;
+        }
+        let c = p.parse_x509().context("Bad certificate")?;
+        let t = c.validity().not_after.timestamp();
+        return DateTime::from_timestamp(t, 0).ok_or_else(|| anyhow!("Bad expiry {t}"));
+    }
+    Err(anyhow!("No certificate in PEM"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health() {
+        let now = Utc::now();
+        assert!(flush(now - Duration::minutes(5), now).ok);
+        assert!(!flush(now - Duration::hours(1), now).ok);
+
+        let expiry = cert_expiry(include_bytes!("../testdata/acme-cert.pem")).unwrap();
+        assert_eq!(expiry.to_rfc3339(), "2026-04-01T00:00:00+00:00");
+        assert!(cert_expiry(b"").is_err());
+
+        let dir = std::env::temp_dir().join(format!("fridgyeast-health-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(cert(&dir, now), Check::fail("No certificate yet"));
+        std::fs::write(dir.join("cached_cert_abc"), include_bytes!("../testdata/acme-cert.pem")).unwrap();
+        let march = DateTime::parse_from_rfc3339("2026-03-01T00:00:00Z").unwrap().to_utc();
+        assert_eq!(cert(&dir, march), Check::ok("Expires 2026-04-01 (31 days)"));
+        assert!(!cert(&dir, march + Duration::days(20)).ok);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut h = Health::default();
+        h.add("fridge", Check::ok("Responding"));
+        assert!(h.ok);
+        h.add("flush", Check::fail("Never saved"));
+        assert!(!h.ok);
+        assert!(h.is_ok("fridge"));
+        assert!(!h.is_ok("sensors"));
+    }
+}
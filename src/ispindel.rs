@@ -0,0 +1,93 @@
+//! Reports posted by iSpindel floating hydrometers, using their "HTTP" service
+
+use serde::{Deserialize, Serialize};
+
+/// What an iSpindel's gravity formula gives
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum GravityUnits {
+    #[default]
+    Sg,
+    Plato,
+}
+
+/// As sent by iSpindel firmware, fields vary a bit between versions.
+/// Others such as `ID`, `interval` and `RSSI` are ignored.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Report {
+    pub name: String,
+    pub token: Option<String>,
+    pub angle: Option<f32>,
+    pub temperature: Option<f32>,
+    /// "C", "F" or "K"
+    pub temp_units: Option<String>,
+    pub battery: Option<f32>,
+    /// Plato or specific gravity depending on the iSpindel's formula
+    pub gravity: Option<f32>,
+    /// "G" or "P", only sent by some firmware
+    pub gravity_unit: Option<String>,
+}
+
+impl Report {
+    /// Sensor id for the temperature, so it can be given an alias like any other sensor
+    pub fn sensor_id(&self) -> String {
+        let name: String = self.name.chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+            .take(30)
+            .collect();
+        format!("ispindel-{name}")
+    }
+
+    pub fn celsius(&self) -> Option<f32> {
+        let t = self.temperature?;
+        match self.temp_units.as_deref().unwrap_or("C") {
+            "F" => Some((t - 32.0) * 5.0 / 9.0),
+            "K" => Some(t - 273.15),
+            _ => Some(t),
+        }
+    }
+
+    /// Gravity as SG, in the report's units if it has them, otherwise `default`
+    pub fn specific_gravity(&self, default: GravityUnits) -> Option<f32> {
+        let g = self.gravity?;
+        let units = match self.gravity_unit.as_deref() {
+            Some("G") => GravityUnits::Sg,
+            Some("P") => GravityUnits::Plato,
+            _ => default,
+        };
+        match units {
+            GravityUnits::Sg => Some(g),
+            GravityUnits::Plato => Some(plato_to_sg(g)),
+        }
+    }
+}
+
+pub fn plato_to_sg(p: f32) -> f32 {
+    1.0 + p / (258.6 - (p / 258.2) * 227.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let r: Report = serde_json::from_str(r#"{"name":"iSpindel 1","ID":6717102,
+            "token":"secret","angle":58.4,"temperature":68.0,"temp_units":"F",
+            "battery":4.1,"gravity":12.0,"interval":900,"RSSI":-71}"#).unwrap();
+        assert_eq!(r.sensor_id(), "ispindel-iSpindel1");
+        assert_eq!(r.celsius(), Some(20.0));
+        assert!((r.specific_gravity(GravityUnits::Plato).unwrap() - 1.0484).abs() < 0.0005);
+        assert_eq!(r.specific_gravity(GravityUnits::Sg), Some(12.0));
+
+        let r: Report = serde_json::from_str(r#"{"name":"blue","temperature":19.5,
+            "gravity":1.012,"gravity_unit":"G"}"#).unwrap();
+        assert_eq!(r.celsius(), Some(19.5));
+        assert_eq!(r.specific_gravity(GravityUnits::Plato), Some(1.012));
+
+        // 1 °P is still Plato
+        let r: Report = serde_json::from_str(r#"{"name":"blue","gravity":1.0,
+            "gravity_unit":"P"}"#).unwrap();
+        assert!((r.specific_gravity(GravityUnits::Sg).unwrap() - 1.0039).abs() < 0.0005);
+    }
+}
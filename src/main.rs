@@ -20,6 +20,17 @@ mod web;
 mod actzero_pubsub;
 mod timeseries;
 mod rusqlmem;
+mod export;
+mod graph;
+mod aliases;
+mod filter;
+mod ispindel;
+mod batch;
+mod recipe;
+mod output;
+mod supervisor;
+mod sdnotify;
+mod health;
 
 use crate::config::Config;
 
@@ -28,31 +39,87 @@ async fn wait_exit() -> Result<()> {
     // see https://github.com/stjepang/async-io/blob/master/examples/unix-signal.rs
     use async_io::Async;
     use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use signal_hook::consts::signal::{SIGINT, SIGTERM};
 
     let (w, mut r) = Async::<UnixStream>::pair()?;
-    signal_hook::low_level::pipe::register(signal_hook::consts::signal::SIGINT, w.get_ref().try_clone()?)?;
-    signal_hook::low_level::pipe::register(signal_hook::consts::signal::SIGTERM, w.get_ref().try_clone()?)?;
+    // a second signal exits immediately, in case cleanup gets stuck
+    let term_now = Arc::new(AtomicBool::new(false));
+    for sig in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(sig, 1, term_now.clone())?;
+        signal_hook::low_level::pipe::register(sig, w.get_ref().try_clone()?)?;
+    }
     debug!("Waiting for exit signal");
 
     // Receive a byte that indicates the Ctrl-C signal occurred.
     r.read_exact(&mut [0]).await?;
-    // Make sure we exit normally if something goes wrong during cleanup
-    signal_hook::low_level::emulate_default_handler(signal_hook::consts::signal::SIGINT).unwrap_or_else(
-        |e| warn!("Couldn't restore SIGINT handler {}", e));
-    signal_hook::low_level::emulate_default_handler(signal_hook::consts::signal::SIGTERM).unwrap_or_else(
-        |e| warn!("Couldn't restore SIGTERM handler {}", e));
+    term_now.store(true, Ordering::Relaxed);
     info!("Exiting with signal");
     Ok(())
 }
 
-fn run(args: &Args) -> Result<()> {
-    // load config, make it static
-    let mut cf = config::Config::load(&args.config)?;
+/// Reloads the config for each SIGHUP, only returns on failure
+async fn reload_on_hup(args: &Args, live: config::Live,
+    fridge: WeakAddr<fridge::Fridge>) -> Result<()> {
+    use async_io::Async;
+    use std::os::unix::net::UnixStream;
+    use signal_hook::consts::signal::SIGHUP;
+
+    let (w, mut r) = Async::<UnixStream>::pair()?;
+    signal_hook::low_level::pipe::register(SIGHUP, w.into_inner()?)?;
+    loop {
+        r.read_exact(&mut [0]).await?;
+        info!("Reloading {} for SIGHUP", args.config);
+        if let Err(e) = reload(args, &live, &fridge).await {
+            error!("Config not reloaded: {e:#}");
+        }
+    }
+}
+
+/// Logs what changed, and applies the settings that don't need a restart
+async fn reload(args: &Args, live: &config::Live, fridge: &WeakAddr<fridge::Fridge>) -> Result<()> {
+    let mut new = config::Config::load(&args.config)?;
+    apply_args(&mut new, args);
+    let old = live.get();
+    let changes = config::diff(old, &new);
+    if changes.is_empty() {
+        info!("Config is unchanged");
+        return Ok(())
+    }
+    for c in &changes {
+        if c.is_live() {
+            info!("Config {}: {} -> {}", c.key, c.old, c.new);
+        } else {
+            warn!("Config {}: {} -> {}, needs a restart", c.key, c.old, c.new);
+        }
+    }
+    if changes.iter().any(|c| c.is_live()) {
+        let mut applied = old.clone();
+        applied.take_live(&new);
+        let applied: &'static Config = Box::leak(Box::new(applied));
+        live.set(applied);
+        call!(fridge.set_config(applied)).await?;
+    }
+    Ok(())
+}
+
+/// Command line options override the config file
+fn apply_args(cf: &mut Config, args: &Args) {
     cf.debug = args.debug;
     cf.testmode = args.test;
     cf.nowait = args.nowait;
     cf.dryrun = args.dryrun;
     cf.testssl = args.testssl;
+    if cf.testmode {
+        cf.sensor_interval = 1;
+    }
+}
+
+fn run(args: &Args) -> Result<()> {
+    // load config, make it static
+    let mut cf = config::Config::load(&args.config)?;
+    apply_args(&mut cf, args);
 
     debug!("Running in debug mode");
     if cf.testmode {
@@ -60,36 +127,44 @@ fn run(args: &Args) -> Result<()> {
         if cf.testssl {
             info!("Using real ssl though");
         }
-        cf.sensor_interval = 1;
     }
     if cf.dryrun {
         info!("Running in dry run mode");
     }
 
     let cf : &'static Config = Box::leak(Box::new(cf));
+    let live = config::Live::new(cf);
     // start actor system
     let spawner = act_zero::runtimes::async_std::Runtime;
-    let fridge = Addr::new(&spawner, fridge::Fridge::try_new(cf)?)?;
-    let webserver = web::listen_http(fridge.downgrade(), cf);
+    let notify = std::sync::Arc::new(sdnotify::SdNotify::from_env());
+    let fridge = fridge::Fridge::try_new(cf, notify.clone())?;
+    let (heartbeat, output) = fridge.supervision();
+    let supervisor = supervisor::Supervisor::start(cf, heartbeat, output, notify.clone())?;
+    let fridge = Addr::new(&spawner, fridge)?;
+    let webserver = web::listen_http(fridge.downgrade(), live.clone(), notify.clone());
 
     let webserver = webserver.fuse();
     let exit = wait_exit().fuse();
+    let reloader = reload_on_hup(args, live, fridge.downgrade()).fuse();
     let mut fridge_done = fridge.downgrade().termination().fuse();
-    futures::pin_mut!(webserver, exit);
+    futures::pin_mut!(webserver, exit, reloader);
 
     let allwaiting = async {
         let s = select! {
             res = webserver => res,
+            res = reloader => res,
             _ = fridge_done => Err(anyhow!("main controller problem")),
             _ = exit => Ok(()),
         };
         s
     };
     let res = async_std::task::block_on(allwaiting);
+    notify.stopping();
     // make sure the fridge finishes regardless
     let final_fridge_done = fridge.termination();
     std::mem::drop(fridge);
     async_std::task::block_on(final_fridge_done);
+    std::mem::drop(supervisor);
     res
 }
 
@@ -0,0 +1,592 @@
+//! The relay switching the fridge: a gpio line or a network smart plug
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use async_std::{future, task};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::config::Config;
+
+pub trait FridgeOutput: Send {
+    fn set(&mut self, on: bool) -> Result<()>;
+
+    /// The actual relay state, `None` if it can't be read back
+    fn get(&mut self) -> Result<Option<bool>>;
+
+    /// For logs
+    fn describe(&self) -> String;
+
+    /// Checks the relay is `on` after `set()`
+    fn verify(&mut self, on: bool) -> Result<()> {
+        match self.get()? {
+            Some(actual) if actual != on => {
+                bail!("{} reads back {} after turning {}", self.describe(),
+                    onoff(actual), onoff(on))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn onoff(on: bool) -> &'static str {
+    if on { "on" } else { "off" }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputKind {
+    Gpio,
+    /// Tasmota firmware, `/cm?cmnd=Power`
+    Tasmota,
+    /// Shelly gen1 API, `/relay/0`
+    Shelly,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct OutputConfig {
+    pub kind: OutputKind,
+    /// Hostname or address of a network relay, with an optional port
+    pub host: Option<String>,
+    /// Relay number on a multi-relay device, from 0
+    pub relay: u32,
+    /// Seconds between checking the relay is in the expected state
+    pub readback_interval: u64,
+    /// Seconds for network requests
+    pub timeout: u64,
+    /// The gpio chip device
+    pub chip: String,
+    /// Finds the gpio line by name rather than `fridge_gpio_pin`
+    pub line_name: Option<String>,
+    /// The relay is energised by a low level. Off, the safe level, is then high.
+    pub active_low: bool,
+    /// Consumer label for the gpio line, shown by `gpioinfo`
+    pub label: String,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            kind: OutputKind::Gpio,
+            host: None,
+            relay: 0,
+            readback_interval: 60,
+            timeout: 3,
+            chip: "/dev/gpiochip0".into(),
+            line_name: None,
+            active_low: false,
+            label: "fridge".into(),
+        }
+    }
+}
+
+/// The output is shared with the supervisor, so it can turn it off if `Fridge` wedges
+pub type SharedOutput = Arc<Mutex<Box<dyn FridgeOutput>>>;
+
+/// Locks the output. A panic while it was held doesn't matter, the relay state is
+/// read back anyway.
+pub fn lock(o: &SharedOutput) -> MutexGuard<'_, Box<dyn FridgeOutput>> {
+    o.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Like `lock()`, but `None` if something else holds it
+pub fn try_lock(o: &SharedOutput) -> Option<MutexGuard<'_, Box<dyn FridgeOutput>>> {
+    match o.try_lock() {
+        Ok(g) => Some(g),
+        Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+/// Runs `f` with the output locked in a blocking thread, a network relay can take
+/// a while to answer
+pub fn blocking<T, F>(o: &SharedOutput, f: F) -> task::JoinHandle<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut dyn FridgeOutput) -> T + Send + 'static,
+{
+    let o = o.clone();
+    task::spawn_blocking(move || f(lock(&o).as_mut()))
+}
+
+/// The configured output, or `FakeOutput` for testmode and dryrun
+pub fn make(config: &Config) -> Result<SharedOutput> {
+    Ok(Arc::new(Mutex::new(make_output(config)?)))
+}
+
+fn make_output(config: &Config) -> Result<Box<dyn FridgeOutput>> {
+    if config.testmode || config.dryrun {
+        return Ok(Box::new(FakeOutput::new()))
+    }
+    let oc = &config.output;
+    let host = || oc.host.clone().ok_or_else(|| anyhow!("output.host is needed for {:?}", oc.kind));
+    let timeout = Duration::from_secs(oc.timeout.max(1));
+    let out: Box<dyn FridgeOutput> = match oc.kind {
+        OutputKind::Gpio => Box::new(GpioOutput::new(oc, config.fridge_gpio_pin)?),
+        OutputKind::Tasmota => Box::new(Tasmota { host: host()?, relay: oc.relay, timeout }),
+        OutputKind::Shelly => Box::new(Shelly { host: host()?, relay: oc.relay, timeout }),
+    };
+    info!("Fridge output is {}", out.describe());
+    Ok(out)
+}
+
+pub struct GpioOutput {
+    line: gpio_cdev::LineHandle,
+    desc: String,
+}
+
+impl GpioOutput {
+    /// Requests the line set to off. `pin` is used unless the config has a `line_name`.
+    pub fn new(oc: &OutputConfig, pin: u32) -> Result<Self> {
+        let mut chip = gpio_cdev::Chip::new(&oc.chip)
+            .with_context(|| format!("{} failed", oc.chip))?;
+        let line = match &oc.line_name {
+            Some(name) => find_line(&chip, name)?,
+            None => chip.get_line(pin)
+                .with_context(|| format!("gpio line {pin} failed"))?,
+        };
+        let desc = match &oc.line_name {
+            Some(name) => format!("gpio {} line {} '{name}'", oc.chip, line.offset()),
+            None => format!("gpio {} line {pin}", oc.chip),
+        };
+        let mut flags = gpio_cdev::LineRequestFlags::OUTPUT;
+        if oc.active_low {
+            flags |= gpio_cdev::LineRequestFlags::ACTIVE_LOW;
+        }
+        let line = line
+            .request(flags, 0, &oc.label)
+            .with_context(|| format!("{desc} output failed"))?;
+        Ok(GpioOutput { line, desc })
+    }
+}
+
+fn find_line(chip: &gpio_cdev::Chip, name: &str) -> Result<gpio_cdev::Line> {
+    for line in chip.lines() {
+        if line.info()?.name() == Some(name) {
+            return Ok(line)
+        }
+    }
+    bail!("No gpio line named '{name}' on {}", chip.path().display())
+}
+
+impl Drop for GpioOutput {
+    fn drop(&mut self) {
+        // the kernel keeps the level after the line is released. Logical 0 is
+        // off, the line was requested active low if the config says so.
+        if let Err(e) = self.line.set_value(0) {
+            error!("Failed setting {} off at shutdown: {e}", self.desc);
+        }
+    }
+}
+
+impl FridgeOutput for GpioOutput {
+    fn set(&mut self, on: bool) -> Result<()> {
+        self.line.set_value(on.into()).context("Couldn't change pin")
+    }
+
+    fn get(&mut self) -> Result<Option<bool>> {
+        let v = self.line.get_value().context("Couldn't read pin")?;
+        Ok(Some(v != 0))
+    }
+
+    fn describe(&self) -> String {
+        self.desc.clone()
+    }
+}
+
+/// Only logs, for testmode and dryrun
+pub struct FakeOutput {
+    on: bool,
+}
+
+impl FakeOutput {
+    pub fn new() -> Self {
+        FakeOutput { on: false }
+    }
+}
+
+impl FridgeOutput for FakeOutput {
+    fn set(&mut self, on: bool) -> Result<()> {
+        debug!("fridge turns {}", onoff(on));
+        self.on = on;
+        Ok(())
+    }
+
+    fn get(&mut self) -> Result<Option<bool>> {
+        Ok(Some(self.on))
+    }
+
+    fn describe(&self) -> String {
+        "fake".into()
+    }
+}
+
+pub struct Tasmota {
+    host: String,
+    relay: u32,
+    timeout: Duration,
+}
+
+impl Tasmota {
+    fn power(&self, cmd: &str) -> Result<bool> {
+        let n = self.relay + 1;
+        let v = http_get(&self.host, &format!("/cm?cmnd=Power{n}{cmd}"), self.timeout)?;
+        // single relay devices reply with POWER rather than POWER1
+        let state = v.get(format!("POWER{n}")).or_else(|| v.get("POWER"))
+            .and_then(|s| s.as_str())
+            .ok_or_else(|| anyhow!("No POWER in Tasmota response {v}"))?;
+        match state {
+            "ON" => Ok(true),
+            "OFF" => Ok(false),
+            _ => bail!("Unexpected Tasmota power '{state}'"),
+        }
+    }
+}
+
+impl FridgeOutput for Tasmota {
+    fn set(&mut self, on: bool) -> Result<()> {
+        let now = self.power(if on { "%20On" } else { "%20Off" })?;
+        if now != on {
+            bail!("Tasmota relay is still {}", onoff(now));
+        }
+        Ok(())
+    }
+
+    fn get(&mut self) -> Result<Option<bool>> {
+        self.power("").map(Some)
+    }
+
+    fn describe(&self) -> String {
+        format!("Tasmota {} relay {}", self.host, self.relay)
+    }
+
+    /// `set()` already checked the reply
+    fn verify(&mut self, _on: bool) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Shelly {
+    host: String,
+    relay: u32,
+    timeout: Duration,
+}
+
+impl Shelly {
+    fn relay(&self, query: &str) -> Result<bool> {
+        let v = http_get(&self.host, &format!("/relay/{}{query}", self.relay), self.timeout)?;
+        v.get("ison").and_then(|s| s.as_bool())
+            .ok_or_else(|| anyhow!("No ison in Shelly response {v}"))
+    }
+}
+
+impl FridgeOutput for Shelly {
+    fn set(&mut self, on: bool) -> Result<()> {
+        let now = self.relay(if on { "?turn=on" } else { "?turn=off" })?;
+        if now != on {
+            bail!("Shelly relay is still {}", onoff(now));
+        }
+        Ok(())
+    }
+
+    fn get(&mut self) -> Result<Option<bool>> {
+        self.relay("").map(Some)
+    }
+
+    fn describe(&self) -> String {
+        format!("Shelly {} relay {}", self.host, self.relay)
+    }
+
+    /// `set()` already checked the reply
+    fn verify(&mut self, _on: bool) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A relay fault that shows in the fridge temperature
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stuck {
+    /// Still cooling while off
+    On,
+    /// Not cooling while on
+    Off,
+}
+
+/// Fridge air falling by this much over the window while off means it's still cooling
+const STUCK_ON_FALL: f32 = 0.5;
+/// Fridge air should fall at least this much over the window while on
+const STUCK_OFF_FALL: f32 = 0.1;
+
+/// Watches the fridge temperature for a relay that isn't doing what it's told.
+/// The fridge keeps cooling briefly after turning off, and takes a while to
+/// start cooling, so only the latter part of a long on or off period is judged.
+pub struct StuckDetector {
+    /// How long since switching before judging, the last half is looked at
+    time: Duration,
+    on: bool,
+    switched: Instant,
+    samples: VecDeque<(Instant, f32)>,
+}
+
+impl StuckDetector {
+    pub fn new(time: Duration, now: Instant) -> Self {
+        StuckDetector {
+            time,
+            on: false,
+            switched: now,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn set_time(&mut self, time: Duration) {
+        self.time = time;
+    }
+
+    pub fn switched(&mut self, on: bool, now: Instant) {
+        self.on = on;
+        self.switched = now;
+        self.samples.clear();
+    }
+
+    /// Adds a fridge temperature, returning a fault if there seems to be one.
+    /// A fridge that's on can sit flat once it's cold, so it's only judged stuck
+    /// off when rising or still warmer than `warm`.
+    pub fn add(&mut self, fridge: f32, warm: f32, now: Instant) -> Option<Stuck> {
+        if self.time.is_zero() {
+            return None
+        }
+        let window = self.time / 2;
+        self.samples.push_back((now, fridge));
+        while self.samples.front().is_some_and(|(t, _)| now - *t > window) {
+            self.samples.pop_front();
+        }
+        if now - self.switched < self.time {
+            return None
+        }
+        let (first_time, first) = *self.samples.front()?;
+        // need most of the window
+        if now - first_time < window * 3 / 4 {
+            return None
+        }
+        let change = fridge - first;
+        let rising = change > STUCK_OFF_FALL;
+        if !self.on && change < -STUCK_ON_FALL {
+            Some(Stuck::On)
+        } else if self.on && change > -STUCK_OFF_FALL && (rising || fridge > warm) {
+            Some(Stuck::Off)
+        } else {
+            None
+        }
+    }
+}
+
+/// A minimal HTTP client, the plugs answer small json responses. The whole
+/// request has `timeout`, including DNS. Callers are in threads that can block,
+/// `Fridge` runs output calls with `spawn_blocking`.
+fn http_get(host: &str, path: &str, timeout: Duration) -> Result<Value> {
+    task::block_on(future::timeout(timeout, http_get_async(host, path)))
+        .map_err(|_| anyhow!("No response from {host} within {}s", timeout.as_secs()))?
+}
+
+async fn http_get_async(host: &str, path: &str) -> Result<Value> {
+    let hostport = host_port(host);
+    let mut s = TcpStream::connect(&hostport).await
+        .with_context(|| format!("Connecting to {host}"))?;
+    let req = format!("GET {path} HTTP/1.0\r\nHost: {hostport}\r\nConnection: close\r\n\r\n");
+    s.write_all(req.as_bytes()).await?;
+
+    let mut resp = vec![];
+    s.take(100_000).read_to_end(&mut resp).await.with_context(|| format!("Reading from {host}"))?;
+    let resp = String::from_utf8_lossy(&resp);
+    let (head, body) = resp.split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow!("Bad response from {host}"))?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        bail!("{host} replied '{status}'");
+    }
+    serde_json::from_str(body).with_context(|| format!("Bad json from {host}"))
+}
+
+/// Adds port 80 unless `host` has a port. IPv6 addresses can be bare or in brackets.
+fn host_port(host: &str) -> String {
+    if host.parse::<SocketAddr>().is_ok() {
+        return host.into()
+    }
+    if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        return SocketAddr::new(ip, 80).to_string()
+    }
+    match host.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => host.into(),
+        _ => format!("{host}:80"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// Stands in for a smart plug. Returns the address and the requested paths.
+    fn plug_stub(reply: fn(&str, &mut bool) -> String) -> (String, Arc<Mutex<Vec<String>>>) {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = l.local_addr().unwrap().to_string();
+        let paths = Arc::new(Mutex::new(vec![]));
+        let p = paths.clone();
+        std::thread::spawn(move || {
+            let mut on = false;
+            for s in l.incoming() {
+                let mut s = s.unwrap();
+                let mut req = String::new();
+                let mut buf = [0u8; 1000];
+                while !req.ends_with("\r\n\r\n") {
+                    let n = s.read(&mut buf).unwrap();
+                    req.push_str(&String::from_utf8_lossy(&buf[..n]));
+                }
+                let path = req.split_whitespace().nth(1).unwrap().to_string();
+                let body = reply(&path, &mut on);
+                p.lock().unwrap().push(path);
+                write!(s, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{body}").unwrap();
+            }
+        });
+        (addr, paths)
+    }
+
+    #[test]
+    fn network_relays() {
+        let (host, paths) = plug_stub(|path, on| {
+            if path.ends_with("On") { *on = true }
+            if path.ends_with("Off") { *on = false }
+            format!(r#"{{"POWER":"{}"}}"#, if *on { "ON" } else { "OFF" })
+        });
+        let mut t = Tasmota { host, relay: 0, timeout: Duration::from_secs(2) };
+        assert_eq!(t.get().unwrap(), Some(false));
+        t.set(true).unwrap();
+        assert_eq!(t.get().unwrap(), Some(true));
+        assert_eq!(*paths.lock().unwrap(),
+            ["/cm?cmnd=Power1", "/cm?cmnd=Power1%20On", "/cm?cmnd=Power1"]);
+
+        let (host, paths) = plug_stub(|path, on| {
+            if path.ends_with("turn=on") { *on = true }
+            // a relay that won't turn off
+            format!(r#"{{"ison":{on},"has_timer":false}}"#)
+        });
+        let mut s = Shelly { host, relay: 1, timeout: Duration::from_secs(2) };
+        s.set(true).unwrap();
+        assert!(s.set(false).is_err());
+        assert_eq!(s.get().unwrap(), Some(true));
+        assert_eq!(paths.lock().unwrap()[0], "/relay/1?turn=on");
+
+        // nothing listening
+        let mut t = Tasmota { host: "127.0.0.1:1".into(), relay: 0, timeout: Duration::from_secs(1) };
+        assert!(t.get().is_err());
+        // connects but never replies
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut t = Tasmota { host: l.local_addr().unwrap().to_string(), relay: 0,
+            timeout: Duration::from_secs(1) };
+        assert!(t.get().is_err());
+
+        assert_eq!(host_port("plug.lan"), "plug.lan:80");
+        assert_eq!(host_port("plug.lan:8080"), "plug.lan:8080");
+        assert_eq!(host_port("192.168.1.50"), "192.168.1.50:80");
+        assert_eq!(host_port("fd00::50"), "[fd00::50]:80");
+        assert_eq!(host_port("[fd00::50]"), "[fd00::50]:80");
+        assert_eq!(host_port("[fd00::50]:8080"), "[fd00::50]:8080");
+    }
+
+    #[test]
+    fn fake_output() {
+        let mut c = Config::example();
+        c.output.kind = OutputKind::Tasmota;
+        c.output.host = Some("192.0.2.1".into());
+        c.dryrun = true;
+        assert_eq!(lock(&make(&c).unwrap()).describe(), "fake");
+        c.dryrun = false;
+        c.testmode = true;
+        assert_eq!(lock(&make(&c).unwrap()).describe(), "fake");
+    }
+
+    /// Needs a gpio-sim chip with a line named "relay", such as from
+    /// `modprobe gpio-sim` and configfs:
+    /// ```text
+    /// cd /sys/kernel/config/gpio-sim && mkdir -p fy/bank0/line3
+    /// echo 4 > fy/bank0/num_lines; echo relay > fy/bank0/line3/name; echo 1 > fy/live
+    /// GPIO_SIM_CHIP=/dev/gpiochipN cargo test -- --ignored gpio_sim
+    /// ```
+    #[test]
+    #[ignore]
+    fn gpio_sim() {
+        let chip = std::env::var("GPIO_SIM_CHIP").expect("GPIO_SIM_CHIP is needed");
+        let oc = OutputConfig {
+            chip,
+            line_name: Some("relay".into()),
+            active_low: true,
+            ..Default::default()
+        };
+        let mut g = GpioOutput::new(&oc, 0).unwrap();
+        assert!(g.describe().contains("line 3"));
+        assert_eq!(g.get().unwrap(), Some(false));
+        g.set(true).unwrap();
+        g.verify(true).unwrap();
+        // the physical level is inverted
+        let mut c = gpio_cdev::Chip::new(&oc.chip).unwrap();
+        assert!(c.get_line(3).unwrap().info().unwrap().is_active_low());
+        drop(g);
+
+        let missing = OutputConfig { line_name: Some("nothing".into()), ..oc };
+        assert!(GpioOutput::new(&missing, 0).is_err());
+    }
+
+    #[test]
+    fn stuck() {
+        let t0 = Instant::now();
+        let t = |m: u64| t0 + Duration::from_secs(m * 60);
+        let mut d = StuckDetector::new(Duration::from_secs(1800), t0);
+
+        // off and warming up, as expected
+        d.switched(false, t(0));
+        assert!((0..60).all(|m| d.add(4.0 + m as f32 * 0.05, 8.0, t(m)).is_none()));
+
+        // off but still getting colder
+        d.switched(false, t(60));
+        let r: Vec<_> = (60..120).map(|m| d.add(10.0 - (m - 60) as f32 * 0.1, 8.0, t(m))).collect();
+        // cooling after turning off is expected at first
+        assert!(r[..30].iter().all(|s| s.is_none()));
+        assert_eq!(r[59], Some(Stuck::On));
+
+        // on and cooling
+        d.switched(true, t(120));
+        assert!((120..200).all(|m| d.add(10.0 - (m - 120) as f32 * 0.05, 8.0, t(m)).is_none()));
+
+        // on but flat
+        d.switched(true, t(200));
+        let r: Vec<_> = (200..240).map(|m| d.add(12.0, 8.0, t(m))).collect();
+        assert_eq!(r[39], Some(Stuck::Off));
+
+        // on and flat but cold, it has reached equilibrium
+        d.switched(true, t(240));
+        assert!((240..300).all(|m| d.add(2.0, 8.0, t(m)).is_none()));
+
+        // on, cold but warming up
+        d.switched(true, t(300));
+        let r: Vec<_> = (300..340).map(|m| d.add(2.0 + (m - 300) as f32 * 0.02, 8.0, t(m))).collect();
+        assert_eq!(r[39], Some(Stuck::Off));
+
+        // disabled
+        let mut d = StuckDetector::new(Duration::ZERO, t0);
+        d.switched(true, t(0));
+        assert!((0..100).all(|m| d.add(12.0, 8.0, t(m)).is_none()));
+    }
+}
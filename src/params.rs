@@ -10,6 +10,7 @@ use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 
+use chrono::{DateTime, offset::Utc};
 use serde::{Serialize,Deserialize};
 
 
@@ -27,8 +28,63 @@ pub struct Params {
     pub fridge_range_lower: f32,
     pub fridge_range_upper: f32,
     pub overshoot_factor: f32,
+
+    /// Forces the fridge on or off for a while, rather than automatic control
+    #[serde(default)]
+    pub manual: Option<ManualOverride>,
+
+    /// Incremented on each change, so that stale updates can be rejected
+    #[serde(default)]
+    pub revision: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ManualMode {
+    On,
+    Off,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ManualOverride {
+    pub mode: ManualMode,
+    /// Unix seconds, automatic control resumes after this
+    pub until: i64,
+}
+
+impl ManualOverride {
+    pub fn until_time(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp(self.until, 0).unwrap_or_default()
+    }
+
+    /// Not yet expired
+    pub fn active(&self, now: DateTime<Utc>) -> bool {
+        self.until_time() > now
+    }
+
+    pub fn describe(&self) -> String {
+        let mode = match self.mode {
+            ManualMode::On => "on",
+            ManualMode::Off => "off",
+        };
+        format!("forced {mode} until {}", self.until_time()
+            .with_timezone(&chrono::Local).format("%a %e %b %H:%M"))
+    }
 }
 
+/// An update was based on an older revision of the params.
+/// Contains the current params.
+#[derive(Debug)]
+pub struct StaleParams(pub Params);
+
+impl std::fmt::Display for StaleParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Params were changed elsewhere, now revision {}", self.0.revision)
+    }
+}
+
+impl std::error::Error for StaleParams {}
+
 impl Params {
     const FILENAME: &'static str = "fridgyeast.conf";
     pub fn defaults() -> Params {
@@ -40,6 +96,8 @@ impl Params {
             fridge_range_lower: 3.0,
             fridge_range_upper: 3.0,
             overshoot_factor: 0.2,
+            manual: None,
+            revision: 0,
             }
     }
 
@@ -69,6 +127,17 @@ impl Params {
         })
     }
 
+    /// `new` with the next revision, if it was based on this one. The override
+    /// is kept, it's only changed by `Fridge::set_override()`.
+    pub fn updated(&self, mut new: Params) -> Result<Params, StaleParams> {
+        if new.revision != self.revision {
+            return Err(StaleParams(self.clone()));
+        }
+        new.revision += 1;
+        new.manual = self.manual.clone();
+        Ok(new)
+    }
+
     pub fn save(&self, config: &Config) -> Result<()> {
         let params_file = config.params_dir.join(Params::FILENAME);
         let af = atomicwrites::AtomicFile::new(params_file, atomicwrites::AllowOverwrite);
@@ -79,3 +148,29 @@ impl Params {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revision() {
+        let cur = Params {
+            revision: 5,
+            manual: Some(ManualOverride { mode: ManualMode::Off, until: 1_700_000_000 }),
+            ..Params::defaults()
+        };
+
+        let new = Params { fridge_setpoint: 12.0, manual: None, ..cur.clone() };
+        let p = cur.updated(new).unwrap();
+        assert_eq!(p.revision, 6);
+        assert_eq!(p.fridge_setpoint, 12.0);
+        assert_eq!(p.manual, cur.manual);
+
+        // based on an older revision, the current values come back
+        let stale = Params { fridge_setpoint: 10.0, revision: 4, ..cur.clone() };
+        let StaleParams(p) = cur.updated(stale).unwrap_err();
+        assert_eq!(p.revision, 5);
+        assert_eq!(p.fridge_setpoint, cur.fridge_setpoint);
+    }
+}
@@ -0,0 +1,233 @@
+//! BeerXML and BeerJSON recipe import, to start a batch with a fermentation profile
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use chrono::{DateTime, offset::Utc};
+use roxmltree::Node;
+use serde_json::Value;
+
+use super::batch::{Batch, Step};
+use super::ispindel::plato_to_sg;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recipe {
+    pub name: String,
+    pub style: Option<String>,
+    pub yeast: Option<String>,
+    /// As SG
+    pub og: Option<f32>,
+    pub fg: Option<f32>,
+    pub steps: Vec<Step>,
+}
+
+/// Parses the first recipe in a BeerXML or BeerJSON file
+pub fn parse(s: &str) -> Result<Recipe> {
+    let s = s.trim_start_matches('\u{feff}').trim_start();
+    let r = if s.starts_with('<') {
+        parse_beerxml(s).context("Reading BeerXML")?
+    } else {
+        parse_beerjson(s).context("Reading BeerJSON")?
+    };
+    if r.name.trim().is_empty() {
+        bail!("Recipe has no name");
+    }
+    Ok(r)
+}
+
+impl Recipe {
+    /// A new batch from the recipe. `until_attenuation` (percent) lets the first
+    /// step end early once fermentation is that far along.
+    pub fn into_batch(self, now: DateTime<Utc>, gravity_series: &str,
+        until_attenuation: Option<f32>) -> Result<Batch> {
+        let og = self.og.ok_or_else(|| anyhow!("Recipe has no OG"))?;
+        let mut profile = self.steps;
+        if let Some(first) = profile.first_mut() {
+            first.until_attenuation = until_attenuation;
+        }
+        let b = Batch {
+            name: self.name.chars().take(100).collect(),
+            og,
+            target_fg: self.fg.filter(|fg| *fg < og),
+            started: now.timestamp(),
+            gravity_series: gravity_series.into(),
+            style: self.style,
+            yeast: self.yeast,
+            profile,
+            step: 0,
+            step_started: now.timestamp(),
+        };
+        b.check()?;
+        Ok(b)
+    }
+}
+
+fn parse_beerxml(s: &str) -> Result<Recipe> {
+    let doc = roxmltree::Document::parse(s)?;
+    let recipe = doc.descendants()
+        .find(|n| n.has_tag_name("RECIPE"))
+        .ok_or_else(|| anyhow!("No RECIPE"))?;
+
+    let style = child(recipe, "STYLE").and_then(|n| text(n, "NAME"));
+    let yeast = child(recipe, "YEASTS")
+        .and_then(|n| child(n, "YEAST"))
+        .and_then(|n| text(n, "NAME"));
+
+    // BeerXML is always metric, ages in days
+    let stages = [("Primary", "PRIMARY"), ("Secondary", "SECONDARY"), ("Tertiary", "TERTIARY")];
+    let count = num(recipe, "FERMENTATION_STAGES").map(|c| c as usize).unwrap_or(stages.len());
+    let steps = stages.iter()
+        .take(count)
+        .map_while(|(name, tag)| {
+            let setpoint = num(recipe, &format!("{tag}_TEMP"))?;
+            let days = num(recipe, &format!("{tag}_AGE")).unwrap_or(0.0);
+            Some(Step::new(name, setpoint, days))
+        })
+        .collect();
+
+    Ok(Recipe {
+        name: text(recipe, "NAME").unwrap_or_default(),
+        style,
+        yeast,
+        og: num(recipe, "OG"),
+        fg: num(recipe, "FG"),
+        steps,
+    })
+}
+
+fn child<'a, 'i>(n: Node<'a, 'i>, tag: &str) -> Option<Node<'a, 'i>> {
+    n.children().find(|c| c.has_tag_name(tag))
+}
+
+/// Text of a direct child
+fn text(n: Node, tag: &str) -> Option<String> {
+    child(n, tag)
+        .and_then(|c| c.text())
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+}
+
+fn num(n: Node, tag: &str) -> Option<f32> {
+    text(n, tag).and_then(|t| t.parse().ok())
+}
+
+fn parse_beerjson(s: &str) -> Result<Recipe> {
+    let v: Value = serde_json::from_str(s)?;
+    let recipe = &v["beerjson"]["recipes"][0];
+    if !recipe.is_object() {
+        bail!("No recipes");
+    }
+    let string = |v: &Value| v.as_str().map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+
+    let mut steps = vec![];
+    if let Some(fs) = recipe["fermentation"]["fermentation_steps"].as_array() {
+        for (i, st) in fs.iter().enumerate() {
+            let setpoint = unit_value(&st["start_temperature"], celsius)
+                .transpose()?
+                .ok_or_else(|| anyhow!("Step {} has no temperature", i + 1))?;
+            let days = unit_value(&st["step_time"], days).transpose()?.unwrap_or(0.0);
+            let name = string(&st["name"]).unwrap_or_else(|| format!("Step {}", i + 1));
+            steps.push(Step::new(&name, setpoint, days));
+        }
+    }
+
+    Ok(Recipe {
+        name: string(&recipe["name"]).unwrap_or_default(),
+        style: string(&recipe["style"]["name"]),
+        yeast: string(&recipe["ingredients"]["culture_additions"][0]["name"]),
+        og: unit_value(&recipe["original_gravity"], gravity).transpose()?,
+        fg: unit_value(&recipe["final_gravity"], gravity).transpose()?,
+        steps,
+    })
+}
+
+/// A BeerJSON `{"unit": .., "value": ..}`, converted. `None` if it's missing.
+fn unit_value(v: &Value, conv: fn(&str, f32) -> Result<f32>) -> Option<Result<f32>> {
+    let value = v["value"].as_f64()? as f32;
+    let unit = v["unit"].as_str().unwrap_or_default();
+    Some(conv(unit, value))
+}
+
+fn celsius(unit: &str, v: f32) -> Result<f32> {
+    match unit {
+        "C" => Ok(v),
+        "F" => Ok((v - 32.0) * 5.0 / 9.0),
+        _ => bail!("Unknown temperature unit '{unit}'"),
+    }
+}
+
+fn days(unit: &str, v: f32) -> Result<f32> {
+    match unit {
+        "sec" => Ok(v / 86400.0),
+        "min" => Ok(v / 1440.0),
+        "hr" => Ok(v / 24.0),
+        "day" => Ok(v),
+        "week" => Ok(v * 7.0),
+        _ => bail!("Unknown time unit '{unit}'"),
+    }
+}
+
+fn gravity(unit: &str, v: f32) -> Result<f32> {
+    match unit {
+        "sg" => Ok(v),
+        // close enough for a target
+        "plato" | "brix" => Ok(plato_to_sg(v)),
+        _ => bail!("Unknown gravity unit '{unit}'"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 0.001
+    }
+
+    #[test]
+    fn recipes() {
+        let r = parse(include_str!("../testdata/pale-ale.xml")).unwrap();
+        assert_eq!(r.name, "Burton Pale Ale");
+        assert_eq!(r.style.as_deref(), Some("English Pale Ale"));
+        assert_eq!(r.yeast.as_deref(), Some("Burton Ale"));
+        assert_eq!((r.og, r.fg), (Some(1.056), Some(1.015)));
+        // only the 2 stages used
+        assert_eq!(r.steps, vec![Step::new("Primary", 20.0, 4.0), Step::new("Secondary", 17.0, 10.0)]);
+
+        let r = parse(include_str!("../testdata/lager.xml")).unwrap();
+        assert_eq!(r.yeast.as_deref(), Some("Bohemian Lager"));
+        assert_eq!(r.fg, None);
+        assert_eq!(r.steps.len(), 3);
+        assert_eq!(r.steps[2], Step::new("Tertiary", 1.0, 28.0));
+
+        let r = parse(include_str!("../testdata/no-og.xml")).unwrap();
+        assert_eq!(r.steps, vec![Step::new("Primary", 19.0, 7.0)]);
+        assert!(r.into_batch(Utc::now(), "", None).is_err());
+
+        let r = parse(include_str!("../testdata/saison.json")).unwrap();
+        assert_eq!(r.name, "Farmhouse Saison");
+        assert_eq!(r.style.as_deref(), Some("Saison"));
+        assert_eq!(r.yeast.as_deref(), Some("Belle Saison"));
+        assert_eq!((r.og, r.fg), (Some(1.058), Some(1.004)));
+        let names: Vec<_> = r.steps.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["Pitch", "Free rise", "Cold crash"]);
+        assert_eq!(r.steps[2].setpoint, 2.0);
+        assert_eq!(r.steps[2].days, 2.0);
+
+        let r = parse(include_str!("../testdata/imperial-units.json")).unwrap();
+        assert!(close(r.og.unwrap(), 1.061));
+        assert!(close(r.fg.unwrap(), 1.016));
+        assert!(close(r.steps[0].setpoint, 18.889));
+        assert_eq!(r.steps[0].days, 14.0);
+
+        let now = Utc::now();
+        let b = r.into_batch(now, "blue.gravity", Some(70.0)).unwrap();
+        assert_eq!(b.name, "Oatmeal Stout");
+        assert_eq!(b.yeast.as_deref(), Some("London Ale III"));
+        assert_eq!(b.profile[0].until_attenuation, Some(70.0));
+        assert_eq!(b.started, now.timestamp());
+
+        assert!(parse("<RECIPES></RECIPES>").is_err());
+        assert!(parse(r#"{"beerjson": {"recipes": []}}"#).is_err());
+        assert!(parse("nonsense").is_err());
+    }
+}
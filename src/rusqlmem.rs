@@ -14,6 +14,8 @@ use async_std::sync::Mutex as AsyncMutex;
 
 use base64::Engine;
 
+use chrono::{DateTime,offset::Utc};
+
 /// Provides a SQLite in-memory database that is automatically loaded/saved to a
 /// backing database on disk.
 pub struct RusqlMem {
@@ -23,6 +25,8 @@ pub struct RusqlMem {
 	file_path: std::path::PathBuf,
 	/// Sqlite path to open the in-memory db
 	mem_path: std::path::PathBuf,
+	/// Loading counts, the file then matches memory
+	last_flush: std::sync::Mutex<DateTime<Utc>>,
 }
 
 impl RusqlMem {
@@ -54,12 +58,13 @@ impl RusqlMem {
 			filedb: Arc::new(AsyncMutex::new(filedb)),
 			file_path: p.into(),
 			mem_path,
+			last_flush: std::sync::Mutex::new(Utc::now()),
 		};
 		r.load_to_mem()?;
 		Ok(r)
 	}
 
-	pub fn db(&self) -> std::sync::MutexGuard<Connection> {
+	pub fn db(&self) -> std::sync::MutexGuard<'_, Connection> {
 		self.memdb.lock().unwrap()
 	}
 
@@ -83,10 +88,16 @@ impl RusqlMem {
 		// fiddly syntax, otherwise compiler can't guess the Error type
 		if r.is_ok() {
 			debug!("Flushed {:?}", self.file_path);
+			*self.last_flush.lock().unwrap() = Utc::now();
 		}
 		r
 	}
 
+	/// When the last successful flush to disk finished, or the file was loaded
+	pub fn last_flush(&self) -> DateTime<Utc> {
+		*self.last_flush.lock().unwrap()
+	}
+
 	fn load_to_mem(&self) -> Result<()> {
 		let f = &*block_on(self.filedb.lock());
 		let mut m = self.db();
@@ -0,0 +1,152 @@
+//! systemd `Type=notify` readiness, status text and watchdog pings
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use anyhow::{Context, Result};
+
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Sends to `$NOTIFY_SOCKET`. Does nothing when not run by systemd.
+pub struct SdNotify {
+    target: Option<(UnixDatagram, SocketAddr)>,
+    web_bound: AtomicBool,
+    sensors_read: AtomicBool,
+    ready: AtomicBool,
+    last_status: Mutex<String>,
+}
+
+impl SdNotify {
+    pub fn from_env() -> Self {
+        let n = match std::env::var_os("NOTIFY_SOCKET") {
+            Some(path) => SdNotify::new(&path.to_string_lossy()),
+            None => Ok(SdNotify::disabled()),
+        };
+        n.unwrap_or_else(|e| {
+            warn!("Not notifying systemd: {e:#}");
+            SdNotify::disabled()
+        })
+    }
+
+    fn disabled() -> Self {
+        SdNotify {
+            target: None,
+            web_bound: AtomicBool::new(false),
+            sensors_read: AtomicBool::new(false),
+            ready: AtomicBool::new(false),
+            last_status: Mutex::new(String::new()),
+        }
+    }
+
+    /// `path` can start with `@` for the abstract namespace
+    pub fn new(path: &str) -> Result<Self> {
+        let addr = match path.strip_prefix('@') {
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)?
+            }
+            None => SocketAddr::from_pathname(path)?,
+        };
+        let sock = UnixDatagram::unbound()?;
+        Ok(SdNotify {
+            target: Some((sock, addr)),
+            ..SdNotify::disabled()
+        })
+    }
+
+    fn send(&self, msg: &str) -> Result<()> {
+        if let Some((sock, addr)) = &self.target {
+            sock.send_to_addr(msg.as_bytes(), addr).context("Sending to NOTIFY_SOCKET")?;
+        }
+        Ok(())
+    }
+
+    fn log_send(&self, msg: &str) {
+        if let Err(e) = self.send(msg) {
+            warn!("{e:#}");
+        }
+    }
+
+    /// The web server is listening
+    pub fn web_bound(&self) {
+        self.web_bound.store(true, Ordering::Relaxed);
+        self.check_ready();
+    }
+
+    /// A sensor read has finished once, even if it failed or found nothing
+    pub fn sensors_read(&self) {
+        if !self.sensors_read.swap(true, Ordering::Relaxed) {
+            self.check_ready();
+        }
+    }
+
+    fn check_ready(&self) {
+        if self.web_bound.load(Ordering::Relaxed) && self.sensors_read.load(Ordering::Relaxed)
+            && !self.ready.swap(true, Ordering::Relaxed) {
+            debug!("Notifying systemd ready");
+            self.log_send("READY=1");
+        }
+    }
+
+    /// One line shown by `systemctl status`, only sent when it changes
+    pub fn status(&self, status: &str) {
+        let mut last = self.last_status.lock().unwrap_or_else(|e| e.into_inner());
+        if *last != status {
+            status.clone_into(&mut last);
+            // a newline would start another assignment
+            self.log_send(&format!("STATUS={}", status.replace('\n', " ")));
+        }
+    }
+
+    /// For `WatchdogSec`, only call while the control loop is running
+    pub fn watchdog(&self) {
+        self.log_send("WATCHDOG=1");
+    }
+
+    pub fn stopping(&self) {
+        self.log_send("STOPPING=1");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn sdnotify() {
+        let path = std::env::temp_dir().join(format!("fridgyeast-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sock = UnixDatagram::bind(&path).unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let recv = || {
+            let mut buf = [0u8; 200];
+            let n = sock.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        };
+
+        let n = SdNotify::new(&path.to_string_lossy()).unwrap();
+        n.status("wort 18.2° fridge 4.1° cooling");
+        assert_eq!(recv(), "STATUS=wort 18.2° fridge 4.1° cooling");
+        // unchanged, not sent again
+        n.status("wort 18.2° fridge 4.1° cooling");
+
+        // ready needs both
+        n.sensors_read();
+        n.web_bound();
+        n.web_bound();
+        assert_eq!(recv(), "READY=1");
+        n.watchdog();
+        assert_eq!(recv(), "WATCHDOG=1");
+
+        // nothing queued
+        sock.set_nonblocking(true).unwrap();
+        assert!(sock.recv(&mut [0u8; 10]).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        // no socket is fine
+        SdNotify::disabled().watchdog();
+    }
+}
@@ -9,8 +9,10 @@ use act_zero::*;
 use act_zero::runtimes::async_std::Timer;
 use act_zero::timer::Tick;
 
-use std::time::Duration;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
 
 use async_std::io::BufReader;
 use async_std::fs::File;
@@ -20,15 +22,28 @@ use async_std::fs::read_to_string;
 
 use std::str::FromStr;
 use rand::Rng;
+use chrono::Utc;
 
 use super::types::*;
-use super::config::Config;
+use super::config::{Config, SensorReader};
+use super::sdnotify::SdNotify;
 use crate::actzero_pubsub::Subscriber;
 
+fn lock(busy: &Mutex<HashSet<String>>) -> MutexGuard<'_, HashSet<String>> {
+    busy.lock().unwrap_or_else(|e| e.into_inner())
+}
+
 pub struct OneWireSensor {
     config: &'static Config,
     target: WeakAddr<dyn Subscriber<Readings>>,
+    /// Told after each read, whether or not it worked
+    systemd: Arc<SdNotify>,
     timer: Timer,
+    /// Sensors that have had their resolution set
+    resolution_set: HashSet<String>,
+    /// Sensors with a read still running, such as after a timeout. A hung read
+    /// holds a blocking thread, so the sensor is skipped until it finishes.
+    busy: Arc<Mutex<HashSet<String>>>,
 }
 
 #[async_trait]
@@ -44,6 +59,8 @@ impl Tick for OneWireSensor {
                     warn!("Failed reading sensor: {}", e);
                 }
             };
+            // ready even with no sensors found, /readyz shows the problem
+            self.systemd.sensors_read();
         }
         Produces::ok(())
     }
@@ -64,34 +81,78 @@ impl Actor for OneWireSensor {
 
 impl OneWireSensor {
 
-    pub fn new(config: &'static Config, target: WeakAddr<dyn Subscriber<Readings>>) -> Self {
+    pub fn new(config: &'static Config, target: WeakAddr<dyn Subscriber<Readings>>,
+        systemd: Arc<SdNotify>) -> Self {
         OneWireSensor {
             config,
             target,
+            systemd,
             timer: Timer::default(),
+            resolution_set: HashSet::new(),
+            busy: Default::default(),
         }
     }
 
-    async fn get_readings(&self) -> Result<Readings> {
+    async fn get_readings(&mut self) -> Result<Readings> {
         let mut r = Readings::new();
 
-        let names = self.sensor_names().await?;
-        for n in &names {
-            match self.read_sensor(n).await {
-                Ok(s) => r.add(n, s),
-                Err(e) => debug!("Error reading sensors {}: {}", n, e)
+        let sensors = find_sensors(Path::new(&self.config.sensor_base_dir),
+            &self.config.sensor_buses, &self.config.sensor_families).await?;
+        for (n, dir) in &sensors {
+            self.set_resolution(n, dir).await;
+        }
+
+        // Each conversion takes up to 750ms, so read them all at once.
+        // A hung sensor only loses its own reading.
+        let timeout = Duration::from_secs(self.config.sensor_timeout);
+        let reader = self.config.sensor_reader;
+        let reads = sensors.iter().map(|(n, dir)| {
+            let busy = self.busy.clone();
+            let (n, dir) = (n.clone(), dir.clone());
+            async move {
+                if !lock(&busy).insert(n.clone()) {
+                    return (n, Err(anyhow!("Previous read hasn't finished")))
+                }
+                let start = Instant::now();
+                // a timeout detaches the task, it's still busy until the read returns
+                let b = busy.clone();
+                let name = n.clone();
+                let task = async_std::task::spawn(async move {
+                    let res = Self::read_one(reader, &dir).await;
+                    lock(&b).remove(&name);
+                    res.map(|temp| (temp, Utc::now(), start.elapsed()))
+                });
+                match async_std::future::timeout(timeout, task).await {
+                    Ok(res) => (n, res),
+                    Err(_) => (n, Err(anyhow!("Timed out after {timeout:?}"))),
+                }
+            }
+        });
+
+        for (n, res) in futures::future::join_all(reads).await {
+            match res {
+                Ok((s, time, latency)) => r.add(&n, Reading { time, latency, ..Reading::new(s, Source::OneWire) }),
+                Err(e) => {
+                    debug!("Error reading sensors {}: {}", n, e);
+                    r.failed.push(n.clone());
+                }
             }
         }
 
+        r.calibrate(&self.config.calibration);
         debug!("sensor step {:?}", r);
         Ok(r)
     }
 
-    async fn read_sensor(&self, n: &str) -> Result<f32> {
-        let mut path = PathBuf::from(&self.config.sensor_base_dir);
-        path.push(n);
-        path.push("temperature");
-        let s = read_to_string(path).await.context("Error reading w1 sensor")?;
+    async fn read_one(reader: SensorReader, dir: &Path) -> Result<f32> {
+        match reader {
+            SensorReader::Temperature => Self::read_sensor(dir).await,
+            SensorReader::W1Slave => Self::read_w1_slave(dir).await,
+        }
+    }
+
+    async fn read_sensor(dir: &Path) -> Result<f32> {
+        let s = read_to_string(dir.join("temperature")).await.context("Error reading w1 sensor")?;
         let temp = f32::from_str(str::trim(&s)).context("Sensor reading isn't a number")? / 1000.;
         // w1-gpio sometimes spuriously returns 85deg
         if temp == 85. {
@@ -100,23 +161,169 @@ impl OneWireSensor {
         Ok(temp)
     }
 
-    async fn sensor_names(&self) -> Result<Vec<String>> {
-        // TODO: needs to handle multiple busses.
-        let mut path = PathBuf::from(&self.config.sensor_base_dir);
-        path.push("w1_master_slaves");
+    /// Reads the scratchpad, retrying if the CRC is bad
+    async fn read_w1_slave(dir: &Path) -> Result<f32> {
+        const TRIES: usize = 3;
+        let mut res = Err(anyhow!("No w1_slave read"));
+        for i in 0..TRIES {
+            res = match read_to_string(dir.join("w1_slave")).await {
+                Ok(s) => parse_w1_slave(&s),
+                Err(e) => Err(anyhow!(e).context("Error reading w1_slave")),
+            };
+            match &res {
+                Ok(_) => break,
+                Err(e) => debug!("{} try {}: {e:#}", dir.display(), i + 1),
+            }
+        }
+        res
+    }
+
+    /// Sets the resolution once per sensor, for families that have it
+    async fn set_resolution(&mut self, n: &str, dir: &Path) {
+        let Some(bits) = self.config.sensor_resolution else {
+            return
+        };
+        // Config::check() has limited it to 9 to 12
+        if self.resolution_set.contains(n) {
+            return
+        }
+        // only once, even if it fails
+        self.resolution_set.insert(n.to_string());
+        if n.starts_with("10-") {
+            // ds18s20 is fixed at 9 bits
+            return
+        }
+        match async_std::fs::write(dir.join("resolution"), format!("{bits}\n")).await {
+            Ok(()) => info!("Set {n} resolution to {bits} bits"),
+            Err(e) => warn!("Couldn't set {n} resolution: {e}"),
+        }
+    }
+}
+
+/// Dallas/Maxim 1-wire CRC8
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for b in data {
+        let mut b = *b;
+        for _ in 0..8 {
+            let mix = (crc ^ b) & 1;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8c;
+            }
+            b >>= 1;
+        }
+    }
+    crc
+}
+
+/// Parses a w1_slave file, checking the scratchpad CRC.
+/// ```text
+/// 72 01 4b 46 7f ff 0e 10 57 : crc=57 YES
+/// 72 01 4b 46 7f ff 0e 10 57 t=23125
+/// ```
+fn parse_w1_slave(s: &str) -> Result<f32> {
+    let mut lines = s.lines();
+    let (crc_line, temp_line) = lines.next().zip(lines.next())
+        .ok_or_else(|| anyhow!("Short w1_slave"))?;
+
+    let (bytes, status) = crc_line.split_once(':')
+        .ok_or_else(|| anyhow!("Bad w1_slave CRC line"))?;
+    if !status.trim_end().ends_with("YES") {
+        bail!("Bad CRC from kernel");
+    }
+    let bytes = bytes.split_whitespace()
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .context("Bad w1_slave bytes")?;
+    if bytes.len() != 9 {
+        bail!("Scratchpad is {} bytes", bytes.len());
+    }
+    // a missing sensor reads as zeros, which has a valid CRC
+    if bytes.iter().all(|b| *b == 0) || bytes.iter().all(|b| *b == 0xff) {
+        bail!("Sensor not responding");
+    }
+    if crc8(&bytes[..8]) != bytes[8] {
+        bail!("Bad CRC");
+    }
+
+    let t = temp_line.rsplit_once("t=")
+        .ok_or_else(|| anyhow!("No w1_slave temperature"))?.1;
+    let temp = i32::from_str(t.trim()).context("Sensor reading isn't a number")? as f32 / 1000.;
+    // The power-on value is 85°, with 0x0c in the reserved byte.
+    // A real 85° reading can be told apart.
+    if temp == 85. && bytes[0] == 0x50 && bytes[1] == 0x05 && bytes[6] == 0x0c {
+        bail!("Sensor power-on value, not converted");
+    }
+    Ok(temp)
+}
 
-        let f = BufReader::new(File::open(path).await.context("Failed opening w1 device list")?);
-        let mut s = f.lines().collect::<Result<Vec<String>, std::io::Error>>().await
-            .context("Failed reading w1 device list")?;
-        // limit to ds18b20, family code 28
-        s.retain(|n| n.starts_with("28-"));
-        Ok(s)
+/// Finds sensors on all 1-wire bus masters, returning each sensor id with the
+/// directory to read it from.
+/// `base` is either a bus master itself or contains `w1_bus_master*` entries,
+/// unless `buses` lists bus masters explicitly.
+/// A sensor is identified by its id alone so it can move between buses.
+pub async fn find_sensors(base: &Path, buses: &[String], families: &[String])
+    -> Result<Vec<(String, PathBuf)>> {
+    let masters: Vec<PathBuf> = if !buses.is_empty() {
+        buses.iter().map(PathBuf::from).collect()
+    } else if base.join("w1_master_slaves").exists() {
+        vec![base.to_path_buf()]
+    } else {
+        let mut m = vec![];
+        let mut dir = async_std::fs::read_dir(base).await
+            .with_context(|| format!("Failed listing {}", base.display()))?;
+        while let Some(e) = dir.next().await {
+            let e = e?;
+            if e.file_name().to_string_lossy().starts_with("w1_bus_master") {
+                m.push(e.path().into());
+            }
+        }
+        m.sort();
+        m
+    };
+
+    if masters.is_empty() {
+        bail!("No w1 bus masters in {}", base.display());
     }
+
+    let mut sensors = BTreeMap::new();
+    let mut any_ok = false;
+    for m in &masters {
+        let names = match bus_devices(m).await {
+            Ok(n) => n,
+            Err(e) => {
+                warn!("Skipping bus {}: {e:#}", m.display());
+                continue
+            }
+        };
+        any_ok = true;
+        for n in names {
+            let family = n.split('-').next().unwrap_or_default();
+            if families.iter().any(|f| f == family) {
+                // first bus wins if it's somehow on two
+                sensors.entry(n.clone()).or_insert_with(|| m.join(&n));
+            }
+        }
+    }
+
+    if !any_ok {
+        bail!("Failed reading any w1 device list");
+    }
+    Ok(sensors.into_iter().collect())
+}
+
+async fn bus_devices(master: &Path) -> Result<Vec<String>> {
+    let path = master.join("w1_master_slaves");
+    let f = BufReader::new(File::open(path).await.context("Failed opening w1 device list")?);
+    f.lines().collect::<Result<Vec<String>, std::io::Error>>().await
+        .context("Failed reading w1 device list")
 }
 
 pub struct TestSensor {
     config: &'static Config,
     target: WeakAddr<dyn Subscriber<Readings>>,
+    systemd: Arc<SdNotify>,
     timer: Timer,
 }
 
@@ -133,6 +340,8 @@ impl Tick for TestSensor {
                     warn!("Failed reading sensor: {}", e);
                 }
             };
+            // ready even with no sensors found, /readyz shows the problem
+            self.systemd.sensors_read();
         }
         Produces::ok(())
     }
@@ -149,10 +358,12 @@ impl Actor for TestSensor {
 
 impl TestSensor {
 
-    pub fn new(config: &'static Config, target: WeakAddr<dyn Subscriber<Readings>>) -> Self {
+    pub fn new(config: &'static Config, target: WeakAddr<dyn Subscriber<Readings>>,
+        systemd: Arc<SdNotify>) -> Self {
         TestSensor {
             config,
             target,
+            systemd,
             timer: Timer::default(),
         }
     }
@@ -163,11 +374,13 @@ impl TestSensor {
 
     async fn get_readings(&self) -> Result<Readings> {
         let mut r = Readings::new();
-        r.add("ambient", Self::jitter(31.2));
+        let reading = |v| Reading::new(Self::jitter(v), Source::Test);
+        r.add("ambient", reading(31.2));
         r.add(&self.config.wort_name,
-            Self::jitter(Self::try_read("test_wort.txt").await.unwrap_or(18.123)));
+            reading(Self::try_read("test_wort.txt").await.unwrap_or(18.123)));
         r.add(&self.config.fridge_name,
-            Self::jitter(Self::try_read("test_fridge.txt").await.unwrap_or(20.233)));
+            reading(Self::try_read("test_fridge.txt").await.unwrap_or(20.233)));
+        r.calibrate(&self.config.calibration);
         debug!("get_readings {:?}", r);
         Ok(r)
     }
@@ -177,3 +390,72 @@ impl TestSensor {
         Ok(s.trim().parse::<f32>()?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+
+    #[test]
+    fn w1_slave() {
+        let ok = "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n\
+            72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+        assert_eq!(parse_w1_slave(ok).unwrap(), 23.125);
+
+        let neg = "5e ff 4b 46 7f ff 02 10 b6 : crc=b6 YES\n\
+            5e ff 4b 46 7f ff 02 10 b6 t=-10125\n";
+        assert_eq!(parse_w1_slave(neg).unwrap(), -10.125);
+
+        // kernel says NO
+        let no = "72 01 4b 46 7f ff 0e 10 00 : crc=00 NO\n\
+            72 01 4b 46 7f ff 0e 10 00 t=23125\n";
+        assert!(parse_w1_slave(no).is_err());
+        // kernel says YES but a byte is corrupt
+        let bad = "72 01 4b 46 7f ff 0e 11 57 : crc=57 YES\n\
+            72 01 4b 46 7f ff 0e 11 57 t=23125\n";
+        assert!(parse_w1_slave(bad).is_err());
+
+        let zeros = "00 00 00 00 00 00 00 00 00 : crc=00 YES\n\
+            00 00 00 00 00 00 00 00 00 t=0\n";
+        assert!(parse_w1_slave(zeros).is_err());
+
+        let reset = "50 05 4b 46 7f ff 0c 10 1c : crc=1c YES\n\
+            50 05 4b 46 7f ff 0c 10 1c t=85000\n";
+        assert!(parse_w1_slave(reset).is_err());
+
+        assert!(parse_w1_slave("").is_err());
+    }
+
+    #[test]
+    fn multiple_buses() -> Result<()> {
+        let base = std::env::temp_dir().join("fridgyeast-test-w1");
+        let _ = std::fs::remove_dir_all(&base);
+        for (bus, devs) in [
+            ("w1_bus_master1", "28-0000042c6dbb\n10-000802b4c1a2\n"),
+            ("w1_bus_master2", "22-00000a1b2c3d\n3a-000000123456\n28-0000042c6dbb\n"),
+        ] {
+            std::fs::create_dir_all(base.join(bus))?;
+            std::fs::write(base.join(bus).join("w1_master_slaves"), devs)?;
+        }
+        std::fs::create_dir_all(base.join("28-0000042c6dbb"))?;
+
+        let families: Vec<String> = ["28", "10", "22"].map(String::from).into();
+        let s = block_on(find_sensors(&base, &[], &families))?;
+        let ids: Vec<&str> = s.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(ids, ["10-000802b4c1a2", "22-00000a1b2c3d", "28-0000042c6dbb"]);
+        assert_eq!(s[2].1, base.join("w1_bus_master1/28-0000042c6dbb"));
+        assert_eq!(s[1].1, base.join("w1_bus_master2/22-00000a1b2c3d"));
+
+        // a single bus master as the base
+        let s = block_on(find_sensors(&base.join("w1_bus_master2"), &[], &families))?;
+        assert_eq!(s.len(), 2);
+
+        let bus2 = [base.join("w1_bus_master2").to_string_lossy().into_owned()];
+        let s = block_on(find_sensors(&base, &bus2, &["28".into()]))?;
+        assert_eq!(s[0].1, base.join("w1_bus_master2/28-0000042c6dbb"));
+
+        assert!(block_on(find_sensors(&base.join("28-0000042c6dbb"), &[], &families)).is_err());
+        std::fs::remove_dir_all(&base)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+//! Turns the fridge off if the control loop stops, and feeds watchdogs while it runs
+
+#[allow(unused_imports)]
+use log::{debug, info, warn, error};
+
+use anyhow::{Context, Result};
+
+use std::fs::File;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use super::config::Config;
+use super::output::{self, SharedOutput};
+use super::sdnotify::SdNotify;
+use super::types::DurationFormat;
+
+/// How often the supervisor checks
+const INTERVAL: Duration = Duration::from_secs(5);
+
+/// Updated by each `Fridge` tick
+#[derive(Clone)]
+pub struct Heartbeat {
+    start: Instant,
+    /// Milliseconds after `start`
+    last: Arc<AtomicU64>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Heartbeat {
+            start: Instant::now(),
+            last: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn beat(&self) {
+        let ms = self.start.elapsed().as_millis() as u64;
+        self.last.store(ms, Ordering::Relaxed);
+    }
+
+    pub fn age(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Feed the watchdogs, hardware and systemd
+    Healthy,
+    /// Turn the output off and stop feeding watchdogs
+    Stalled,
+    /// Still stalled after turning off, exit so the service is restarted
+    Exit,
+}
+
+/// What to do for a heartbeat age, `timeout` zero disables it
+pub fn judge(age: Duration, timeout: Duration) -> Action {
+    if timeout.is_zero() || age < timeout {
+        Action::Healthy
+    } else if age < timeout * 2 {
+        Action::Stalled
+    } else {
+        Action::Exit
+    }
+}
+
+/// The Linux hardware watchdog. The machine reboots if it isn't written to for
+/// the driver's timeout, around 15 seconds on a Raspberry Pi.
+struct Watchdog {
+    f: File,
+}
+
+impl Watchdog {
+    fn open(path: &str) -> Result<Self> {
+        let f = File::options().write(true).open(path)
+            .with_context(|| format!("Opening watchdog {path}"))?;
+        info!("Using watchdog {path}");
+        Ok(Watchdog { f })
+    }
+
+    fn feed(&mut self) {
+        if let Err(e) = self.f.write_all(b"\0").and_then(|_| self.f.flush()) {
+            warn!("Feeding watchdog failed: {e}");
+        }
+    }
+
+    /// Disarms the watchdog for a clean exit
+    fn close(mut self) {
+        if let Err(e) = self.f.write_all(b"V").and_then(|_| self.f.flush()) {
+            warn!("Closing watchdog failed: {e}");
+        }
+    }
+}
+
+pub struct Supervisor {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    /// Runs in its own thread so that it works even if the async executor is stuck
+    pub fn start(config: &Config, heartbeat: Heartbeat, output: SharedOutput,
+        notify: Arc<SdNotify>) -> Result<Self> {
+        let timeout = Duration::from_secs(config.supervisor_timeout);
+        // a network relay request can hold the output this long
+        let output_wait = Duration::from_secs(config.output.timeout) + INTERVAL;
+        let watchdog = config.watchdog_device.as_deref().map(Watchdog::open).transpose()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let s = stop.clone();
+        let thread = std::thread::Builder::new()
+            .name("supervisor".into())
+            .spawn(move || run(timeout, output_wait, heartbeat, output, watchdog, notify, s))?;
+        Ok(Supervisor { stop, thread: Some(thread) })
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(t) = self.thread.take() {
+            t.thread().unpark();
+            let _ = t.join();
+        }
+    }
+}
+
+fn run(timeout: Duration, output_wait: Duration, heartbeat: Heartbeat, output: SharedOutput,
+    mut watchdog: Option<Watchdog>, notify: Arc<SdNotify>, stop: Arc<AtomicBool>) {
+    let mut state = State::default();
+    while !stop.load(Ordering::Relaxed) {
+        match state.check(heartbeat.age(), timeout, output_wait, &output, Instant::now()) {
+            Action::Healthy => {
+                if let Some(w) = &mut watchdog {
+                    w.feed();
+                }
+                notify.watchdog();
+            }
+            Action::Stalled => (),
+            // the watchdog isn't disarmed, it will reboot if we aren't restarted
+            Action::Exit => std::process::exit(1),
+        }
+        std::thread::park_timeout(INTERVAL);
+    }
+    if let Some(w) = watchdog {
+        w.close();
+    }
+}
+
+/// Kept between checks
+#[derive(Default)]
+struct State {
+    stalled: bool,
+    /// When the output was first found locked while stalled
+    busy_since: Option<Instant>,
+}
+
+impl State {
+    /// Turns the output off while the control loop is stalled. `Exit` once it
+    /// has been stalled too long, or a stuck `Fridge` holds the output.
+    fn check(&mut self, age: Duration, timeout: Duration, output_wait: Duration,
+        output: &SharedOutput, now: Instant) -> Action {
+        let action = judge(age, timeout);
+        match action {
+            Action::Healthy => {
+                if self.stalled {
+                    info!("Control loop is running again");
+                    self.stalled = false;
+                }
+                self.busy_since = None;
+            }
+            Action::Stalled => {
+                if !self.stalled {
+                    error!("Control loop stalled for {}, turning fridge off", age.as_short_str());
+                    self.stalled = true;
+                }
+                // keep trying. Fridge may be stuck while holding the output, so
+                // don't wait for it, exit instead and let the restart turn it off.
+                match output::try_lock(output) {
+                    Some(mut o) => {
+                        self.busy_since = None;
+                        if let Err(e) = o.set(false) {
+                            error!("Supervisor couldn't turn fridge off: {e:#}");
+                        }
+                    }
+                    None => {
+                        let locked = now - *self.busy_since.get_or_insert(now);
+                        if locked >= output_wait {
+                            error!("Output has been locked for {}, exiting", locked.as_short_str());
+                            return Action::Exit
+                        }
+                    }
+                }
+            }
+            Action::Exit => {
+                error!("Control loop stalled for {}, exiting", age.as_short_str());
+            }
+        }
+        action
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::FakeOutput;
+    use std::sync::Mutex;
+
+    #[test]
+    fn supervisor() {
+        let t = Duration::from_secs(60);
+        assert_eq!(judge(Duration::from_secs(10), t), Action::Healthy);
+        assert_eq!(judge(Duration::from_secs(60), t), Action::Stalled);
+        assert_eq!(judge(Duration::from_secs(130), t), Action::Exit);
+        assert_eq!(judge(Duration::from_secs(1000), Duration::ZERO), Action::Healthy);
+
+        let h = Heartbeat::new();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(h.age() >= Duration::from_millis(20));
+        h.beat();
+        assert!(h.age() < Duration::from_millis(20));
+    }
+
+    #[test]
+    fn turns_off() {
+        let t = Duration::from_secs(60);
+        let wait = Duration::from_secs(10);
+        let now = Instant::now();
+        let out: SharedOutput = Arc::new(Mutex::new(Box::new(FakeOutput::new())));
+        output::lock(&out).set(true).unwrap();
+        let mut s = State::default();
+
+        assert_eq!(s.check(Duration::from_secs(10), t, wait, &out, now), Action::Healthy);
+        assert_eq!(output::lock(&out).get().unwrap(), Some(true));
+
+        // a stalled heartbeat turns it off
+        assert_eq!(s.check(Duration::from_secs(70), t, wait, &out, now), Action::Stalled);
+        assert_eq!(output::lock(&out).get().unwrap(), Some(false));
+
+        // a locked output doesn't block, it exits after a while
+        output::lock(&out).set(true).unwrap();
+        let held = output::lock(&out);
+        assert_eq!(s.check(Duration::from_secs(75), t, wait, &out, now), Action::Stalled);
+        assert_eq!(s.check(Duration::from_secs(80), t, wait, &out, now + Duration::from_secs(5)),
+            Action::Stalled);
+        assert_eq!(s.check(Duration::from_secs(85), t, wait, &out, now + wait), Action::Exit);
+        drop(held);
+
+        // running again
+        let mut s = State::default();
+        assert_eq!(s.check(Duration::from_secs(70), t, wait, &out, now), Action::Stalled);
+        assert_eq!(s.check(Duration::from_secs(1), t, wait, &out, now), Action::Healthy);
+        assert!(!s.stalled);
+    }
+}
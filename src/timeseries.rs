@@ -31,7 +31,26 @@ pub struct TimeSeries {
 
 pub type Seq = Vec<(DateTime<Utc>, f32)>;
 
+/// A single stored point, as returned by [`TimeSeries::export`].
+#[derive(Debug, Clone)]
+pub struct ExportRow {
+	pub time: DateTime<Utc>,
+	pub name: String,
+	pub value: f32,
+	/// Number of samples averaged into the point. `None` for stepwise series.
+	pub count: Option<u32>,
+}
+
+/// A discrete occurrence shown on the timeline, such as a dry hop or restart.
+#[derive(Debug, Clone)]
+pub struct Event {
+	pub time: DateTime<Utc>,
+	pub kind: String,
+	pub note: String,
+}
+
 pub const DEFAULT_SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10*60);
+const PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60*60);
 
 impl TimeSeries {
 	pub fn new(p: &Path, quantise_secs: u64, history: Duration) -> Result<Self> {
@@ -42,12 +61,13 @@ impl TimeSeries {
 			prune_timer: Timer::default(),
 			flush_timer: Timer::default(),
 		};
+		Self::upgrade_schema(&ts.db.db())?;
 		Ok(ts)
 	}
 
 	/// Inserts a new datapoint. If points exist within the quantised time
 	/// window the new point will be accumulated as an average.
-	pub async fn add(&self, name: &str, value: f32) -> ActorResult<()> {
+	pub async fn add(&self, name: String, value: f32) -> ActorResult<()> {
 		let mut conn = self.db.db();
 		let t = conn.transaction()?;
 		let dif = Utc::now().timestamp() as u64;
@@ -70,7 +90,6 @@ impl TimeSeries {
 
 	/// Inserts a new datapoint for a stepwise series.
 	pub async fn add_step(&self, name: &str, value: f32) -> ActorResult<()> {
-		println!("add_step {name} {value}");
 		let mut conn = self.db.db();
 		let t = conn.transaction()?;
 		let now = Utc::now().timestamp() as u64;
@@ -78,7 +97,7 @@ impl TimeSeries {
 			"select value from step_points where name = ? and time <= ? order by time desc limit 1", params![name, now],
 			|r| r.get(0))
 			.optional()?;
-		println!("now {now} oldval {oldval:?} value {value}");
+		debug!("add_step {name} {value}, old {oldval:?}");
 		if let Some(ov) = oldval {
 			if ov == value {
 				return Produces::ok(());
@@ -89,6 +108,28 @@ impl TimeSeries {
 		Produces::ok(())
 	}
 
+	pub async fn add_event(&self, kind: String, note: String) -> ActorResult<()> {
+		let now = Utc::now().timestamp();
+		self.db.db().execute("insert into events values (?, ?, ?)", params![now, kind, note])?;
+		Produces::ok(())
+	}
+
+	/// Returns events between `start` and `end` inclusive
+	pub async fn get_events(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> ActorResult<Vec<Event>> {
+		let r: Result<Vec<Event>> = self.db.db()
+		.prepare("select time, kind, note from events where time >= ? and time <= ? order by time")?
+		.query_map(params![start.timestamp(), end.timestamp()], |r| {
+			Ok(Event {
+				time: Self::int_to_time(r.get(0)?),
+				kind: r.get(1)?,
+				note: r.get(2)?,
+			})
+		})?
+		.map(|r| r.context("SQL query"))
+		.collect();
+		Produces::ok(r?)
+	}
+
 	/// Returns points within the history window
 	/// _TODO_: also return one point prior the the window?
 	pub async fn get(&self, name: String, start: DateTime<Utc>) -> ActorResult<Seq> {
@@ -145,11 +186,46 @@ impl TimeSeries {
 		Produces::ok(res)
 	}
 
+	/// Returns the most recent time a stepwise series changed to `value`
+	pub async fn last_step_time(&self, name: String, value: f32) -> ActorResult<Option<DateTime<Utc>>> {
+		let t: Option<i64> = self.db.db().query_row(
+			"select time from step_points where name = ? and value = ? order by time desc limit 1",
+			params![name, value], |r| r.get(0))
+			.optional()?;
+		Produces::ok(t.map(Self::int_to_time))
+	}
+
+	/// Returns the raw stored points for a series between `start` and `end`
+	/// inclusive. Both averaged and stepwise series are handled.
+	pub async fn export(&self, name: String, start: DateTime<Utc>, end: DateTime<Utc>)
+		-> ActorResult<Vec<ExportRow>> {
+		let r: Result<Vec<ExportRow>> = self.db.db()
+		.prepare("select time, name, value, count from points where name = ?1 and time >= ?2 and time <= ?3 \
+			union all \
+			select time, name, value, null from step_points where name = ?1 and time >= ?2 and time <= ?3 \
+			order by time")?
+		.query_map(params![name, start.timestamp(), end.timestamp()], |r| {
+			Ok(ExportRow {
+				time: Self::int_to_time(r.get(0)?),
+				name: r.get(1)?,
+				value: r.get(2)?,
+				count: r.get(3)?,
+			})
+		})?
+		.map(|r| r.context("SQL query"))
+		.collect();
+		Produces::ok(r?)
+	}
+
 	pub async fn save(&self) -> ActorResult<()> {
 		self.db.flush().await?;
 		Produces::ok(())
 	}
 
+	pub async fn last_flush(&self) -> ActorResult<DateTime<Utc>> {
+		Produces::ok(self.db.last_flush())
+	}
+
 	fn int_to_time(i: i64) -> DateTime<Utc> {
 		DateTime::from_timestamp(i, 0).unwrap()
 	}
@@ -159,6 +235,7 @@ impl TimeSeries {
 		let cutoff = self.earliest();
 		self.db.db().execute("delete from points where time < ?", params![cutoff])?;
 		self.db.db().execute("delete from step_points where time < ?", params![cutoff])?;
+		self.db.db().execute("delete from events where time < ?", params![cutoff])?;
 		debug!("Pruned memory db prior to {}", cutoff);
 		Ok(())
 	}
@@ -172,6 +249,13 @@ impl TimeSeries {
 		t.execute("create table step_points (time, name, value)", [])?;
 		t.execute("create unique index points_index on points (name, time)", [])?;
 		t.execute("create unique index step_index on points (name, time)", [])?;
+		t.execute("create table events (time, kind, note)", [])?;
+		Ok(())
+	}
+
+	/// Adds tables missing from databases created by older versions
+	fn upgrade_schema(db: &rusqlite::Connection) -> Result<()> {
+		db.execute("create table if not exists events (time, kind, note)", [])?;
 		Ok(())
 	}
 }
@@ -179,7 +263,7 @@ impl TimeSeries {
 #[async_trait]
 impl Actor for TimeSeries {
     async fn started(&mut self, addr: Addr<Self>) -> ActorResult<()> {
-        self.prune_timer.set_interval_weak(addr.downgrade(), (self.history * 2).to_std()?);
+        self.prune_timer.set_interval_weak(addr.downgrade(), PRUNE_INTERVAL);
         self.flush_timer.set_interval_weak(addr.downgrade(), DEFAULT_SAVE_INTERVAL);
         Produces::ok(())
     }
@@ -221,9 +305,46 @@ use super::*;
 
 #[test]
 fn new_timeseries() -> Result<()> {
-	let t = TimeSeries::new(Path::new("ff.db"), 3, Duration::from_secs(60*60*24*3))?;
-    send!(t.add("wort", 3.2f32));
+	let p = std::env::temp_dir().join("fridgyeast-test-ff.db");
+	let t = TimeSeries::new(&p, 3, Duration::days(3))?;
+	block_on(t.add("wort".into(), 3.2f32)).map_err(|e| anyhow!(e))?;
+	let loaded = t.db.last_flush();
 	block_on(t.db.flush())?;
+	assert!(t.db.last_flush() > loaded);
+	Ok(())
+}
+
+/// Runs an actor method directly
+fn run<T>(f: impl std::future::Future<Output = ActorResult<T>>) -> Result<T> {
+	let p = block_on(f).map_err(|e| anyhow!(e))?;
+	Ok(block_on(p)?)
+}
+
+#[test]
+fn export_both_kinds() -> Result<()> {
+	let p = std::env::temp_dir().join("fridgyeast-test-export.db");
+	let _ = std::fs::remove_file(&p);
+	// a day's quantisation so both points are averaged together
+	let t = TimeSeries::new(&p, 24*60*60, Duration::days(3))?;
+	run(t.add("wort".into(), 3.0f32))?;
+	run(t.add("wort".into(), 4.0f32))?;
+	run(t.add_step("setpoint", 18.0f32))?;
+
+	let start = Utc::now() - Duration::days(2);
+	let end = Utc::now() + Duration::hours(1);
+	let rows = run(t.export("wort".into(), start, end))?;
+	assert_eq!(rows.len(), 1);
+	assert_eq!(rows[0].value, 3.5);
+	assert_eq!(rows[0].count, Some(2));
+
+	let rows = run(t.export("setpoint".into(), start, end))?;
+	assert_eq!(rows.len(), 1);
+	assert_eq!(rows[0].count, None);
+
+	run(t.add_event("dry hop".into(), "50g Citra".into()))?;
+	let events = run(t.get_events(start, end))?;
+	assert_eq!(events.len(), 1);
+	assert_eq!(events[0].note, "50g Citra");
 	Ok(())
 }
 
@@ -1,4 +1,4 @@
-/// Various helpers
+//! Various helpers
 
 use std::collections::HashMap;
 use std::time::{Duration,Instant};
@@ -6,24 +6,98 @@ use std::time::{Duration,Instant};
 use std::cmp;
 use std::cell::Cell;
 
-#[derive(Debug,Clone)]
+use chrono::{DateTime, offset::Utc};
+use serde::Serialize;
+
+use crate::config::Calibration;
+
+/// Where a reading came from
+#[derive(Debug,Clone,Copy,PartialEq,Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    OneWire,
+    Test,
+    ISpindel,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quality {
+    Good,
+    /// The new sample was rejected by the filter, this is an earlier value
+    Held,
+}
+
+#[derive(Debug,Clone,Serialize)]
+pub struct Reading {
+    /// Calibrated temperature
+    pub temp: f32,
+    /// As read from the sensor
+    pub raw: f32,
+    /// When it was measured
+    #[serde(serialize_with = "serialize_time")]
+    pub time: DateTime<Utc>,
+    /// How long the read took
+    pub latency: Duration,
+    pub source: Source,
+    pub quality: Quality,
+}
+
+fn serialize_time<S: serde::Serializer>(t: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&t.to_rfc3339())
+}
+
+impl Reading {
+    /// An uncalibrated reading, measured now
+    pub fn new(v: f32, source: Source) -> Self {
+        Reading {
+            temp: v,
+            raw: v,
+            time: Utc::now(),
+            latency: Duration::ZERO,
+            source,
+            quality: Quality::Good,
+        }
+    }
+
+    pub fn age(&self, now: DateTime<Utc>) -> Duration {
+        (now - self.time).to_std().unwrap_or(Duration::ZERO)
+    }
+}
+
+#[derive(Debug,Clone,Default,Serialize)]
 pub struct Readings {
-    pub temps: HashMap<String, f32>,
+    pub temps: HashMap<String, Reading>,
+    /// Sensors that were found but couldn't be read
+    pub failed: Vec<String>,
 }
 
 impl Readings {
     pub fn new() -> Readings {
         Readings {
             temps: HashMap::new(),
+            failed: vec![],
         }
     }
 
-    pub fn add(&mut self, name: &str, v: f32) {
-        self.temps.insert(name.to_string(), v);
+    pub fn add(&mut self, name: &str, r: Reading) {
+        self.temps.insert(name.to_string(), r);
+    }
+
+    /// Applies calibration to any readings that have one configured
+    pub fn calibrate(&mut self, calibration: &HashMap<String, Calibration>) {
+        for (name, r) in self.temps.iter_mut() {
+            if let Some(c) = calibration.get(name) {
+                r.temp = c.apply(r.raw);
+            }
+        }
     }
 
-    pub fn get_temp(&self, name: &str) -> Option<f32> {
-        self.temps.get(name).copied()
+    /// Returns the temperature if it isn't older than `max_age`
+    pub fn get_temp(&self, name: &str, max_age: Duration) -> Option<f32> {
+        self.temps.get(name)
+            .filter(|r| r.age(Utc::now()) <= max_age)
+            .map(|r| r.temp)
     }
 }
 
@@ -96,7 +170,7 @@ impl StepIntegrator {
         }
     }
 
-    pub fn _set_limit(&mut self, limit: Duration) {
+    pub fn set_limit(&mut self, limit: Duration) {
         self.limit = limit;
         self.trim();
     }
@@ -168,6 +242,14 @@ impl DurationFormat for Duration {
     }
 }
 
+pub fn get_vcs_version() -> &'static str {
+    env!("GIT_REV")
+}
+
+pub fn get_rustc_version() -> &'static str {
+    env!("RUSTC_VER")
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -183,12 +265,18 @@ mod tests {
         let d = Duration::from_millis(20);
         assert_eq!(d.as_short_str(), "0s");
     }
-}
 
-pub fn get_vcs_version() -> &'static str {
-    env!("GIT_REV")
-}
-
-pub fn get_rustc_version() -> &'static str {
-    env!("RUSTC_VER")
+    #[test]
+    fn stale_readings() {
+        use std::time::Duration;
+        use crate::types::{Reading, Readings, Source};
+        let mut r = Readings::new();
+        r.add("wort", Reading::new(18.0, Source::Test));
+        let old = chrono::Utc::now() - chrono::Duration::seconds(120);
+        r.add("fridge", Reading { time: old, ..Reading::new(4.0, Source::Test) });
+        let max_age = Duration::from_secs(60);
+        assert_eq!(r.get_temp("wort", max_age), Some(18.0));
+        assert_eq!(r.get_temp("fridge", max_age), None);
+        assert_eq!(r.get_temp("fridge", Duration::from_secs(180)), Some(4.0));
+    }
 }
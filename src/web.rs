@@ -2,12 +2,11 @@ use std::time::Duration;
 
 #[allow(unused_imports)]
 use log::{debug, info, warn, error};
-use plotters::coord::ranged1d::{NoDefaultFormatting, ValueFormatter};
 
 use std::net::ToSocketAddrs;
 
 
-use crate::Config;
+use crate::config::{self, Config};
 use tide::sessions::Session;
 use anyhow::{Result,anyhow,Context};
 
@@ -21,28 +20,50 @@ use tide::{Request,Response,StatusCode};
 use tide_acme::{AcmeConfig, TideRustlsExt, rustls_acme::caches::DirCache};
 use tide::listener::Listener;
 
-use plotters::prelude::*;
-use plotters::coord::ranged1d::KeyPointHint;
-
 use crate::fridge;
-use crate::params::Params;
+use crate::params::{ManualMode, ManualOverride, Params, StaleParams};
 use crate::types::DurationFormat;
+use crate::export;
+use crate::graph;
+use crate::aliases;
+use crate::ispindel;
+use crate::batch::{Batch, Progress};
+use crate::recipe;
+use crate::health;
+use crate::sdnotify::SdNotify;
+use std::sync::Arc;
 
 #[derive(Clone)]
 struct WebState {
     fridge: WeakAddr<fridge::Fridge>,
-    config: &'static Config,
+    live: config::Live,
 }
 
 impl WebState {
-    fn new(fridge: WeakAddr<fridge::Fridge>, config: &'static Config) -> Self {
+    fn new(fridge: WeakAddr<fridge::Fridge>, live: config::Live) -> Self {
         WebState {
             fridge,
-            config,
+            live,
+        }
+    }
+
+    /// Changes with a reload
+    fn config(&self) -> &'static Config {
+        self.live.get()
+    }
+
+    fn default_range(&self) -> graph::Range {
+        if self.config().testmode {
+            graph::Range::Hour
+        } else {
+            graph::Range::Hours8
         }
     }
 }
 
+/// Narrow enough for a phone. The page reloads the graph at its real width.
+const DEFAULT_GRAPH_WIDTH: u32 = 300;
+
 #[derive(askama::Template,Serialize)]
 #[template(path="numinput.html")]
 struct NumInput {
@@ -98,6 +119,9 @@ struct SetPage<'a> {
     numinputs: Vec<NumInput>,
     yesnoinputs: Vec<YesNoInput>,
     svg: String,
+    ranges: Vec<String>,
+    range: String,
+    event_kinds: &'static [&'static str],
 }
 
 impl<'a> SetPage<'a> {
@@ -114,7 +138,7 @@ async fn handle_set(req: Request<WebState>) -> tide::Result {
     let status = call!(s.fridge.get_status()).await?;
 
     let ses: &Session = req.ext().ok_or_else(|| anyhow!("Missing session"))?;
-    let allowed = s.config.allowed_sessions.contains(ses.id());
+    let allowed = s.config().allowed_sessions.contains(ses.id());
 
     debug!("set with session id {} {}", ses.id(), if allowed { "allowed" } else { "not allowed"} );
 
@@ -126,21 +150,26 @@ async fn handle_set(req: Request<WebState>) -> tide::Result {
 
     debug!("cookies are {:?}", req.cookie("fridgyeast-moreauth"));
 
-    let svg = svg(s).await.unwrap_or_default();
+    let range = s.default_range();
+    let g = graph::Graph::new(range, DEFAULT_GRAPH_WIDTH, None);
+    let svg = graph::svg(&s.fridge, &g).await.unwrap_or_default();
 
     let mut s = SetPage {
         status,
         csrf_blob: "unused", // hopefully SameSite=Strict is enough for now
         allowed,
         cookie_hash: ses.id(),
-        debug: s.config.debug,
-        testmode: s.config.testmode,
+        debug: s.config().debug,
+        testmode: s.config().testmode,
         recent_off_time,
 
         numinputs: vec![],
         yesnoinputs: vec![],
 
         svg,
+        ranges: graph::Range::ALL.iter().map(|r| r.to_string()).collect(),
+        range: range.to_string(),
+        event_kinds: &EVENT_KINDS,
     };
 
     s.yesnoinputs.push(YesNoInput::new("running", "Running"));
@@ -168,111 +197,92 @@ async fn handle_logout(mut req: Request<WebState>) -> tide::Result {
     Ok(tide::Redirect::new("/").into())
 }
 
-struct DegreeValue {
-    lower: f32,
-    upper: f32,
+/// Query parameters for `/history.svg` and `/history.png`
+#[derive(Deserialize)]
+struct HistoryQuery {
+    range: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
 }
 
-impl Ranged for DegreeValue {
-    type ValueType = f32;
-    type FormatOption = NoDefaultFormatting;
-    fn map(&self, value: &Self::ValueType, limit: (i32, i32)) -> i32 {
-        let pix: f32 = (limit.1 - limit.0) as f32;
-        limit.0 + (pix * (value - self.lower) / (self.upper - self.lower)) as i32
-    }
-
-    fn range(&self) -> std::ops::Range<Self::ValueType> {
-        self.lower..self.upper
-    }
-
-    fn key_points<Hint: KeyPointHint>(&self, _hint: Hint) -> Vec<Self::ValueType> {
-        let s = (self.lower as i32) / 10 * 10;
-        let e = (self.upper as i32) / 10 * 10;
-        (s..=e).step_by(10).map(|i| i as f32).collect()
-    }
+fn history_graph(req: &Request<WebState>) -> tide::Result<graph::Graph> {
+    let s = req.state();
+    let q: HistoryQuery = req.query()?;
+    let range = match &q.range {
+        Some(r) => r.parse().map_err(|e| tide::http::Error::from_str(StatusCode::BadRequest, e))?,
+        None => s.default_range(),
+    };
+    Ok(graph::Graph::new(range, q.width.unwrap_or(DEFAULT_GRAPH_WIDTH), q.height))
 }
 
-impl ValueFormatter<f32> for DegreeValue {
-    fn format(v: &f32) -> String {
-        format!("{:.0}°", v)
-    }
-}
+async fn handle_history(req: Request<WebState>) -> tide::Result {
+    let g = history_graph(&req)?;
+    let out = graph::svg(&req.state().fridge, &g).await?;
 
-async fn svg(state: &WebState) -> Result<String> {
+    let resp = Response::builder(200)
+    .body(out)
+    .content_type(tide::http::mime::SVG)
+    .build();
+    Ok(resp)
+}
 
-    let (time1, time_desc) = if state.config.testmode {
-        (chrono::Utc::now() - chrono::Duration::minutes(10), "10 minutes")
-    } else {
-        (chrono::Utc::now() - chrono::Duration::hours(8), "8 hours")
-    };
-    let time2 = chrono::Utc::now();
-    let time_range = time1..time2;
-    let temp_range = DegreeValue { lower: -2f32, upper: 32f32 };
-
-    let worts = call!(state.fridge.history("wort".into(), time1)).await?;
-    let fridges = call!(state.fridge.history("fridge".into(), time1)).await?;
-    let setpoints = call!(state.fridge.history_step("setpoint".into(), time1)).await?;
-    println!("setpoints {setpoints:?}");
-    let mut out = String::new();
-    let w = 300f32;
-    // golden ratio is as good as any I guess
-    let ratio = (1f32 + 5f32.powf(0.5)) / 2f32;
-    let h = w / (ratio);
-    let area = plotters_svg::SVGBackend::with_string(&mut out, (w as u32, h as u32)) .into();
-
-    let amber = RGBColor(0xff, 0xa8, 0);
-    let fridgeblue = RGBColor(0x93, 0xc8, 0xff);
-    let green = RGBColor(0x9a, 0xd7, 0x51);
-    let ruler = RGBColor(0xaa,0xaa,0xaa).stroke_width(1);
-
-    let mut plot = ChartBuilder::on(&area)
-    .y_label_area_size(40)
-    .x_label_area_size(10)
-    .build_cartesian_2d(time_range, temp_range)?;
-
-    plot.configure_mesh()
-    .disable_x_mesh()
-    .disable_x_axis()
-    .axis_style(ruler.stroke_width(0))
-    .bold_line_style(ruler)
-    .set_all_tick_mark_size(1)
-    .x_desc(time_desc)
-    .draw()?;
-
-    plot.draw_series(
-        LineSeries::new(
-            fridges,
-            fridgeblue.stroke_width(3),
-        )
-    )?;
-    plot.draw_series(
-        LineSeries::new(
-            worts,
-            amber.stroke_width(3),
-        )
-    )?;
-    println!("setpoints {setpoints:?}");
-    plot.draw_series(
-        LineSeries::new(
-            setpoints,
-            green.stroke_width(1),
-        )
-    )?;
+async fn handle_history_png(req: Request<WebState>) -> tide::Result {
+    let g = history_graph(&req)?;
+    let out = graph::png(&req.state().fridge, &g).await?;
 
-    // take back 'out'
-    drop(plot);
-    drop(area);
-    Ok(out)
+    let resp = Response::builder(200)
+    .body(out)
+    .content_type(tide::http::mime::PNG)
+    .build();
+    Ok(resp)
 }
 
+/// Query parameters for `/export`. `name` may be a comma separated list of series,
+/// or `events`.
+/// Times are unix seconds or RFC3339.
+#[derive(Deserialize)]
+struct ExportQuery {
+    name: String,
+    start: Option<String>,
+    end: Option<String>,
+    format: Option<String>,
+}
 
-async fn handle_history(req: Request<WebState>) -> tide::Result {
+async fn handle_export(req: Request<WebState>) -> tide::Result {
     let s = req.state();
-    let out = svg(s).await?;
+    let q: ExportQuery = req.query()?;
+
+    let bad = |e: anyhow::Error| tide::http::Error::from_str(StatusCode::BadRequest, e);
+    let format: export::Format = q.format.as_deref().unwrap_or("csv").parse().map_err(bad)?;
+    let start = match &q.start {
+        Some(t) => export::parse_time(t).map_err(bad)?,
+        None => chrono::DateTime::UNIX_EPOCH,
+    };
+    let end = match &q.end {
+        Some(t) => export::parse_time(t).map_err(bad)?,
+        None => chrono::Utc::now(),
+    };
+
+    let body = if q.name == "events" {
+        let events = call!(s.fridge.events(start, end)).await?;
+        format.write_events(&events)?
+    } else {
+        let mut rows = vec![];
+        for name in q.name.split(',') {
+            rows.extend(call!(s.fridge.export(name.to_string(), start, end)).await?);
+        }
+        rows.sort_by_key(|r| r.time);
+        format.write(&rows)?
+    };
 
+    let safe_name: String = q.name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+        .collect();
+    let filename = format!("fridgyeast-{safe_name}.{}", format.extension());
     let resp = Response::builder(200)
-    .body(out)
-    .content_type(tide::http::mime::SVG)
+    .body(body)
+    .content_type(format.mime())
+    .header("Content-Disposition", format!("inline; filename=\"{filename}\""))
     .build();
     Ok(resp)
 }
@@ -295,23 +305,24 @@ async fn handle_register(mut req: Request<WebState>) -> tide::Result {
     let ses: &mut Session = req.session_mut();
     let known = ses.get_raw("known");
     ses.insert("known", true)?;
-    let allowed = s.config.allowed_sessions.contains(ses.id());
+    let allowed = s.config().allowed_sessions.contains(ses.id());
 
     let r = Register {
-        email: &s.config.owner_email,
+        email: &s.config().owner_email,
         cookie_hash: ses.id(),
         known: known.is_some(),
-        debug: s.config.debug,
+        debug: s.config().debug,
         allowed,
     };
     let r = askama_tide::into_response(&r);
     Ok(r)
 }
 
-async fn handle_update(mut req: Request<WebState>) -> tide::Result {
-    let s = req.state().clone();
-    let ses: &mut Session = req.session_mut();
-    let allowed = s.config.allowed_sessions.contains(ses.id());
+/// Checks that a request is allowed to change settings
+fn check_allowed(req: &Request<WebState>) -> tide::Result<()> {
+    let s = req.state();
+    let ses: &Session = req.ext().ok_or_else(|| anyhow!("Missing session"))?;
+    let allowed = s.config().allowed_sessions.contains(ses.id());
 
     if !allowed {
         return Err(tide::http::Error::from_str(403, "Not registered"))
@@ -320,6 +331,12 @@ async fn handle_update(mut req: Request<WebState>) -> tide::Result {
     if req.cookie(CSRF_NAME).is_none() {
         return Err(tide::http::Error::from_str(403, "Bad CSRF"))
     }
+    Ok(())
+}
+
+async fn handle_update(mut req: Request<WebState>) -> tide::Result {
+    check_allowed(&req)?;
+    let s = req.state().clone();
 
     #[derive(Deserialize)]
     struct Update {
@@ -331,11 +348,290 @@ async fn handle_update(mut req: Request<WebState>) -> tide::Result {
         e
         })?;
 
+    #[derive(Serialize)]
+    struct Updated<'a> {
+        params: &'a Params,
+        error: Option<String>,
+    }
+
     // send the params to the fridge
     // note the extra ? is to unwrap the call! itself
-    call!(s.fridge.set_params(update.params)).await?
-    .map(|_| "Updated".into())
-    .map_err(|e| tide::http::Error::from_str(StatusCode::InternalServerError, e))
+    match call!(s.fridge.set_params(update.params)).await? {
+        Ok(p) => {
+            let r = Updated { params: &p, error: None };
+            Ok(Response::builder(StatusCode::Ok)
+                .body(tide::Body::from_json(&r)?)
+                .build())
+        }
+        Err(e) => {
+            if let Some(StaleParams(p)) = e.downcast_ref() {
+                // someone else saved first, give back the current values
+                let r = Updated { params: p, error: Some(e.to_string()) };
+                Ok(Response::builder(StatusCode::Conflict)
+                    .body(tide::Body::from_json(&r)?)
+                    .build())
+            } else {
+                Err(tide::http::Error::from_str(StatusCode::InternalServerError, e))
+            }
+        }
+    }
+}
+
+async fn handle_override(mut req: Request<WebState>) -> tide::Result {
+    check_allowed(&req)?;
+    let s = req.state().clone();
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Mode {
+        On,
+        Off,
+        Auto,
+    }
+
+    #[derive(Deserialize)]
+    struct SetOverride {
+        mode: Mode,
+        /// Required for on and off
+        minutes: Option<u32>,
+    }
+
+    let o: SetOverride = req.body_json().await?;
+    let manual = match o.mode {
+        Mode::Auto => None,
+        Mode::On | Mode::Off => {
+            let minutes = o.minutes.filter(|m| (1..=7*24*60).contains(m))
+                .ok_or_else(|| tide::http::Error::from_str(StatusCode::BadRequest,
+                    "Override needs minutes, up to a week"))?;
+            Some(ManualOverride {
+                mode: if matches!(o.mode, Mode::On) { ManualMode::On } else { ManualMode::Off },
+                until: chrono::Utc::now().timestamp() + minutes as i64 * 60,
+            })
+        }
+    };
+    let p = call!(s.fridge.set_override(manual)).await?
+        .map_err(|e| tide::http::Error::from_str(StatusCode::BadRequest, e))?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(tide::Body::from_json(&p)?)
+        .build())
+}
+
+/// Kinds of event offered on the main page
+const EVENT_KINDS: [&str; 5] = ["note", "dry hop", "gravity", "yeast pitched", "transfer"];
+
+async fn handle_event(mut req: Request<WebState>) -> tide::Result {
+    check_allowed(&req)?;
+    let s = req.state().clone();
+
+    #[derive(Deserialize)]
+    struct NewEvent {
+        kind: String,
+        note: String,
+    }
+
+    let ev: NewEvent = req.body_json().await?;
+    let kind = ev.kind.trim();
+    let note = ev.note.trim();
+    if kind.is_empty() || kind.len() > 40 || note.len() > 200 {
+        return Err(tide::http::Error::from_str(StatusCode::BadRequest, "Bad event"))
+    }
+
+    call!(s.fridge.add_event(kind.into(), note.into())).await?;
+    Ok("Added".into())
+}
+
+/// A row on the sensors page
+struct SensorRow {
+    id: String,
+    alias: String,
+    temp: String,
+    raw: String,
+    seen: String,
+    latency: String,
+    errors: u64,
+}
+
+#[derive(askama::Template)]
+#[template(path="sensors.html")]
+struct SensorsPage {
+    sensors: Vec<SensorRow>,
+    roles: &'static [&'static str],
+    allowed: bool,
+}
+
+async fn handle_sensors(req: Request<WebState>) -> tide::Result {
+    let s = req.state();
+    let allowed = check_allowed(&req).is_ok();
+    let sensors = call!(s.fridge.sensors()).await?;
+
+    let now = chrono::Utc::now();
+    let deg = |t: Option<f32>| t.map(|t| format!("{t:.2}°")).unwrap_or_default();
+    let sensors = sensors.into_iter().map(|i| {
+        let seen = match i.last_seen.and_then(|t| (now - t).to_std().ok()) {
+            Some(d) => format!("{} ago", d.as_short_str()),
+            None => "never".into(),
+        };
+        SensorRow {
+            id: i.id,
+            alias: i.alias.unwrap_or_default(),
+            temp: deg(i.temp),
+            raw: deg(i.raw),
+            seen,
+            latency: i.latency.map(|l| format!("{}ms", l.as_millis())).unwrap_or_default(),
+            errors: i.errors,
+        }
+    }).collect();
+
+    let p = SensorsPage {
+        sensors,
+        roles: &aliases::ROLES,
+        allowed,
+    };
+    Ok(askama_tide::into_response(&p))
+}
+
+async fn handle_set_alias(mut req: Request<WebState>) -> tide::Result {
+    check_allowed(&req)?;
+    let s = req.state().clone();
+
+    #[derive(Deserialize)]
+    struct SetAlias {
+        id: String,
+        alias: String,
+    }
+
+    let a: SetAlias = req.body_json().await?;
+    call!(s.fridge.set_alias(a.id, a.alias)).await?
+        .map_err(|e| tide::http::Error::from_str(StatusCode::BadRequest, e))?;
+    Ok("Saved".into())
+}
+
+#[derive(askama::Template)]
+#[template(path="batch.html")]
+struct BatchPage {
+    batch: Option<Batch>,
+    progress: Option<Progress>,
+    started: String,
+    /// When the current profile step ends by time
+    step_end: String,
+    allowed: bool,
+}
+
+impl BatchPage {
+    fn gravity(&self, g: &Option<f32>) -> String {
+        g.map(|g| format!("{g:.3}")).unwrap_or("?".into())
+    }
+
+    fn percent(&self, v: &Option<f32>) -> String {
+        v.map(|v| format!("{v:.0}%")).unwrap_or("?".into())
+    }
+}
+
+async fn handle_batch(req: Request<WebState>) -> tide::Result {
+    let s = req.state();
+    let allowed = check_allowed(&req).is_ok();
+    let (batch, progress) = call!(s.fridge.batch()).await?;
+    let started = batch.as_ref()
+        .map(|b| b.started_time().with_timezone(&chrono::Local).format("%a %e %b %H:%M").to_string())
+        .unwrap_or_default();
+    let step_end = batch.as_ref()
+        .and_then(|b| b.step_end())
+        .map(|t| t.with_timezone(&chrono::Local).format("%a %e %b %H:%M").to_string())
+        .unwrap_or_default();
+    let progress = progress.map(|mut p| {
+        p.attenuation = p.attenuation.map(|a| a * 100.0);
+        p
+    });
+    let p = BatchPage {
+        batch,
+        progress,
+        started,
+        step_end,
+        allowed,
+    };
+    Ok(askama_tide::into_response(&p))
+}
+
+async fn handle_set_batch(mut req: Request<WebState>) -> tide::Result {
+    check_allowed(&req)?;
+    let s = req.state().clone();
+
+    #[derive(Deserialize)]
+    struct NewBatch {
+        name: String,
+        og: f32,
+        target_fg: Option<f32>,
+        #[serde(default)]
+        gravity_series: String,
+    }
+
+    /// `null` ends the current batch
+    #[derive(Deserialize)]
+    struct SetBatch {
+        batch: Option<NewBatch>,
+    }
+
+    let b: SetBatch = req.body_json().await?;
+    let b = b.batch.map(|b| Batch {
+        name: b.name.trim().to_string(),
+        og: b.og,
+        target_fg: b.target_fg,
+        started: chrono::Utc::now().timestamp(),
+        gravity_series: b.gravity_series.trim().to_string(),
+        style: None,
+        yeast: None,
+        profile: vec![],
+        step: 0,
+        step_started: chrono::Utc::now().timestamp(),
+    });
+    call!(s.fridge.set_batch(b)).await?
+        .map_err(|e| tide::http::Error::from_str(StatusCode::BadRequest, e))?;
+    Ok("Saved".into())
+}
+
+/// Starts a batch from a BeerXML or BeerJSON file in the body
+async fn handle_recipe(mut req: Request<WebState>) -> tide::Result {
+    check_allowed(&req)?;
+    let s = req.state().clone();
+
+    #[derive(Deserialize)]
+    struct RecipeQuery {
+        /// Percent, ends the first step early
+        until_attenuation: Option<f32>,
+    }
+
+    let q: RecipeQuery = req.query()?;
+    let body = req.body_string().await?;
+    let b = recipe::parse(&body)
+        .and_then(|r| r.into_batch(chrono::Utc::now(), "", q.until_attenuation))
+        .map_err(|e| tide::http::Error::from_str(StatusCode::BadRequest, format!("{e:#}")))?;
+    call!(s.fridge.set_batch(Some(b))).await?
+        .map_err(|e| tide::http::Error::from_str(StatusCode::BadRequest, e))?;
+    Ok("Saved".into())
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// iSpindels post here. The token can be in the json or the url.
+async fn handle_ispindel(mut req: Request<WebState>) -> tide::Result {
+    let s = req.state().clone();
+    let Some(want) = &s.config().ispindel_token else {
+        return Err(tide::http::Error::from_str(StatusCode::NotFound, "iSpindel is not enabled"))
+    };
+
+    let q: TokenQuery = req.query()?;
+    let report: ispindel::Report = req.body_json().await?;
+    let token = report.token.as_ref().or(q.token.as_ref());
+    if token != Some(want) {
+        warn!("iSpindel '{}' has the wrong token", report.name);
+        return Err(tide::http::Error::from_str(StatusCode::Forbidden, "Bad token"))
+    }
+
+    call!(s.fridge.add_ispindel(report)).await?;
+    Ok("OK".into())
 }
 
 async fn handle_status(req: Request<WebState>) -> tide::Result {
@@ -348,9 +644,60 @@ async fn handle_status(req: Request<WebState>) -> tide::Result {
     Ok(resp)
 }
 
+/// How long the fridge has to answer a health check
+const HEALTH_DEADLINE: Duration = Duration::from_secs(5);
+
+async fn health(s: &WebState) -> health::Health {
+    let mut h = health::Health::default();
+    let now = chrono::Utc::now();
+    let status = async_std::future::timeout(HEALTH_DEADLINE, async {
+        let status = call!(s.fridge.get_status()).await?;
+        let last_flush = call!(s.fridge.last_flush()).await?;
+        Ok::<_, act_zero::ActorError>((status, last_flush))
+    }).await;
+    match status {
+        Ok(Ok((status, last_flush))) => {
+            h.add("fridge", health::Check::ok("Responding"));
+            h.add("sensors", health::sensors(&status));
+            h.add("output", health::output(&status));
+            h.add("flush", health::flush(last_flush, now));
+        }
+        Ok(Err(e)) => h.add("fridge", health::Check::fail(format!("{e}"))),
+        Err(_) => h.add("fridge", health::Check::fail(
+            format!("No response in {}s", HEALTH_DEADLINE.as_secs()))),
+    }
+    if !s.config().testmode || s.config().testssl {
+        // reads files
+        let dir = s.config().params_dir.clone();
+        h.add("cert", async_std::task::spawn_blocking(move || health::cert(&dir, now)).await);
+    }
+    h
+}
+
+fn health_response(h: &health::Health, ok: bool) -> tide::Result {
+    let code = if ok { StatusCode::Ok } else { StatusCode::ServiceUnavailable };
+    let resp = Response::builder(code)
+    .body(tide::Body::from_json(h)?)
+    .content_type(tide::http::mime::JSON)
+    .build();
+    Ok(resp)
+}
+
+/// Liveness, only fails if the fridge actor is stuck
+async fn handle_healthz(req: Request<WebState>) -> tide::Result {
+    let h = health(req.state()).await;
+    health_response(&h, h.is_ok("fridge"))
+}
+
+/// Fails if anything needs attention
+async fn handle_readyz(req: Request<WebState>) -> tide::Result {
+    let h = health(req.state()).await;
+    health_response(&h, h.ok)
+}
+
 async fn handle_panic(req: Request<WebState>) -> tide::Result {
     let s = req.state();
-    if s.config.testmode {
+    if s.config().testmode {
         panic!("seeing what happens");
     }
     let resp = Response::builder(200)
@@ -370,15 +717,18 @@ fn until_2038() -> Duration {
 
 async fn listen_test(server: tide::Server<WebState>, 
     _config: &'static Config, 
-    addrs: Vec<std::net::SocketAddr>) -> Result<()> {
+    addrs: Vec<std::net::SocketAddr>,
+    notify: Arc<SdNotify>) -> Result<()> {
     let mut listener = server.bind(addrs).await.context("binding web server failed")?;
+    notify.web_bound();
     listener.accept().await.context("web server failed")?;
     Ok(())
 }
 
 async fn listen_ssl(server: tide::Server<WebState>, 
     config: &'static Config, 
-    addrs: Vec<std::net::SocketAddr>) -> Result<()> {
+    addrs: Vec<std::net::SocketAddr>,
+    notify: Arc<SdNotify>) -> Result<()> {
     let contact = format!("mailto:{}", config.owner_email);
     let conf = tide_rustls::TlsListener::build()
             .addrs(&*addrs)
@@ -390,12 +740,16 @@ async fn listen_ssl(server: tide::Server<WebState>,
                 );
 
     let mut listener = server.bind(conf).await.context("binding web server failed")?;
+    notify.web_bound();
     listener.accept().await.context("web server failed")?;
     Ok(())
 }
 
-pub async fn listen_http(fridge: WeakAddr<fridge::Fridge>, config: &'static Config) -> Result<()> {
-    let ws = WebState::new(fridge, config);
+pub async fn listen_http(fridge: WeakAddr<fridge::Fridge>, live: config::Live,
+    notify: Arc<SdNotify>) -> Result<()> {
+    // listening settings need a restart to change
+    let config = live.get();
+    let ws = WebState::new(fridge, live);
     let mut server = tide::with_state(ws);
 
     // Make it return a http error's string as the body.
@@ -432,10 +786,22 @@ pub async fn listen_http(fridge: WeakAddr<fridge::Fridge>, config: &'static Conf
     // url handlers
     server.at("/").get(handle_set);
     server.at("/history.svg").get(handle_history);
+    server.at("/history.png").get(handle_history_png);
+    server.at("/export").get(handle_export);
     server.at("/update").post(handle_update);
+    server.at("/event").post(handle_event);
+    server.at("/sensors").get(handle_sensors);
+    server.at("/sensors").post(handle_set_alias);
+    server.at("/ispindel").post(handle_ispindel);
+    server.at("/batch").get(handle_batch);
+    server.at("/batch").post(handle_set_batch);
+    server.at("/recipe").post(handle_recipe);
+    server.at("/override").post(handle_override);
     server.at("/register").get(handle_register);
     server.at("/logout").get(handle_logout);
     server.at("/status").get(handle_status);
+    server.at("/healthz").get(handle_healthz);
+    server.at("/readyz").get(handle_readyz);
     server.at("/panic").get(handle_panic);
 
     let mut addrs = vec![];
@@ -444,9 +810,9 @@ pub async fn listen_http(fridge: WeakAddr<fridge::Fridge>, config: &'static Conf
     };
 
     if config.testmode && !config.testssl{
-        listen_test(server, config, addrs).await?;
+        listen_test(server, config, addrs, notify).await?;
     } else {
-        listen_ssl(server, config, addrs).await?;
+        listen_ssl(server, config, addrs, notify).await?;
     }
 
     Ok(())
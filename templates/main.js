@@ -31,7 +31,18 @@ function Model(initial_params, csrf_blob, save_allowed) {
             body: JSON.stringify(post_json)})
         .then(response => {
             if (response.ok) {
-                self.emit("status", "Saved")
+                response.json().then(r => {
+                    // later saves are based on this revision
+                    self.params.revision = r.params.revision
+                    self.emit("status", "Saved")
+                })
+            } else if (response.status == 409) {
+                // don't overwrite someone else's change, show it instead
+                response.json().then(r => {
+                    Object.assign(self.params, r.params)
+                    self.emit("replaced", r.params)
+                    self.emit("status", "Not saved, settings were changed elsewhere and are shown now")
+                })
             } else {
                 // seriously?
                 response.blob()
@@ -87,6 +98,23 @@ model.on("edit", function(param, value) {
     }
 })
 
+model.on("replaced", function(params) {
+    // these are the saved values now
+    Object.assign(initial_params, params)
+    Object.keys(allinputs).forEach(name => {
+        const el = document.querySelector("#input_"+name)
+        const oldvalue = el.querySelector(".oldvalue")
+        if (typeof(params[name]) == "boolean") {
+            oldvalue.textContent = params[name] ? 'Yes' : 'No'
+            set_yesnoinput_value(el, params[name])
+        } else {
+            oldvalue.textContent = fixed_value(name, params[name]) + allinputs[name].unit
+            set_numinput_value(el, name, params[name])
+        }
+        oldvalue.classList.remove("modified")
+    })
+})
+
 model.on("status", function(status) {
     document.querySelector("#status").textContent = status
 })
@@ -159,6 +187,77 @@ function setup_yesnoinput(input) {
     set_yesnoinput_value(el, model.params[name]);
 }
 
+// Graph
+let plot_range = "{{range}}"
+
+function load_plot(range) {
+    const el = document.querySelector("#plot_svg")
+    // limit it for giant screens
+    const width = Math.min(Math.max(el.clientWidth, 300), 900)
+    fetch("history.svg?range=" + range + "&width=" + width)
+    .then(response => {
+        if (!response.ok) {
+            throw new Error(response.status + ' ' + response.statusText)
+        }
+        return response.text()
+    })
+    .then(svg => {
+        el.innerHTML = svg
+        plot_range = range
+        document.querySelectorAll(".rangebutton").forEach(b => {
+            b.classList.toggle("onbutton", b.dataset.range == range)
+        })
+    })
+    .catch(e => console.log("Failed loading plot", e))
+}
+
+function setup_ranges() {
+    document.querySelectorAll(".rangebutton").forEach(b => {
+        b.addEventListener("click", function() {
+            load_plot(this.dataset.range)
+            this.blur()
+        })
+    })
+}
+
+function add_event() {
+    const status = document.querySelector("#event_status")
+    const note = document.querySelector("#event_note")
+    const post_json = {
+        kind: document.querySelector("#event_kind").value,
+        note: note.value,
+    }
+    status.textContent = "Adding..."
+    fetch("event",
+        {method: "POST",
+        body: JSON.stringify(post_json)})
+    .then(response => response.text().then(text => {
+        if (response.ok) {
+            status.textContent = "Added"
+            note.value = ""
+            load_plot(plot_range)
+        } else {
+            status.textContent = "Failed: " + response.status + ' ' + text
+        }
+    }))
+}
+
+function set_override(mode, minutes) {
+    const status = document.querySelector("#override_status")
+    status.textContent = "Saving..."
+    fetch("override",
+        {method: "POST",
+        body: JSON.stringify({mode: mode, minutes: minutes})})
+    .then(response => response.text().then(text => {
+        if (response.ok) {
+            // params have a new revision
+            location.reload()
+        } else {
+            status.textContent = "Failed: " + response.status + ' ' + text
+        }
+    }))
+}
+
 window.addEventListener('DOMContentLoaded', (event) => {
     // Hook up events
     if (!model.save_allowed) {
@@ -171,6 +270,25 @@ window.addEventListener('DOMContentLoaded', (event) => {
 
     numinputs.forEach(input => setup_numinput(input))
     yesnoinputs.forEach(input => setup_yesnoinput(input))
+
+    setup_ranges()
+    const eventbutton = document.querySelector("#eventbutton")
+    if (eventbutton) {
+        eventbutton.addEventListener("click", add_event)
+    }
+    const overridebutton = document.querySelector("#overridebutton")
+    if (overridebutton) {
+        overridebutton.addEventListener("click", function() {
+            set_override(document.querySelector("#override_mode").value,
+                Number(document.querySelector("#override_minutes").value))
+        })
+    }
+    const cancel = document.querySelector("#override_cancel")
+    if (cancel) {
+        cancel.addEventListener("click", () => set_override("auto", null))
+    }
+    // the initial plot is small, redraw it to fit
+    load_plot(plot_range)
 })
 
 })() // end presenter